@@ -1,124 +1,298 @@
 // do not remove this line since you are not allowed to use unsafe code
 #![deny(unsafe_code)]
 
-// a similar 'die' macro with the C version
+// a similar 'die' macro with the C version. the failure unwinds to main, which
+// reports it and exits with the status for its kind
 macro_rules! die {
-    ($($arg:tt)*) => {
-        eprintln!("proj3: {}", format_args!($($arg)*));
-        panic!();
+    ($kind:ident, $($arg:tt)*) => {
+        panic::panic_any(Failure::new(ErrorKind::$kind, format!($($arg)*)))
     };
 }
 
+use std::any::Any;
+use std::fmt;
 use std::panic;
 
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::{self, Read, stdin};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use std::io::{self, BufRead, IsTerminal, LineWriter, Read, Write, stdin};
+
+
+//ERRORS
+
+// what went wrong, each kind has its own exit status (see USAGE)
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ErrorKind {
+    // bad command line
+    Usage,
+    // a file couldn't be found, read or written
+    Io,
+    // unbalanced braces, missing arguments, malformed definitions
+    Syntax,
+    // undefined macros, redefinitions, include cycles
+    Semantic,
+    // an include tried to leave the --sandbox root
+    Sandbox,
+    // one of the --max- limits or --timeout was hit
+    Limit,
+}
 
+// status for a panic that didn't come from die!, which is a bug
+const EXIT_INTERNAL: i32 = 101;
+
+impl ErrorKind {
+    fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Usage => 2,
+            ErrorKind::Io => 3,
+            ErrorKind::Syntax => 4,
+            ErrorKind::Semantic => 5,
+            ErrorKind::Sandbox => 6,
+            ErrorKind::Limit => 7,
+        }
+    }
 
-//helpers!!
+    // what --error-format=json calls it
+    fn code(self) -> &'static str {
+        match self {
+            ErrorKind::Usage => "usage",
+            ErrorKind::Io => "io",
+            ErrorKind::Syntax => "syntax",
+            ErrorKind::Semantic => "semantic",
+            ErrorKind::Sandbox => "sandbox",
+            ErrorKind::Limit => "limit",
+        }
+    }
+}
+
+// what die! unwinds with
+struct Failure {
+    kind: ErrorKind,
+    diagnostic: Diagnostic,
+    // the problem has been printed already, all that's left is the exit status
+    reported: bool,
+}
 
-fn is_brace_balanced(s: &str, index: usize) -> bool {
-    if index >= s.len() {
-        return false; // Index out of bounds
+impl Failure {
+    fn new(kind: ErrorKind, message: String) -> Failure {
+        Failure {
+            kind,
+            diagnostic: Diagnostic::new(Severity::Error(kind), kind.code(), None, message),
+            reported: false,
+        }
     }
 
-    let bytes = s.as_bytes();
-    let mut left_brace_count = 0;
-    let mut right_brace_count = 0;
-    let mut i = index;
+    // fails the run with it, for callers that can't carry on without what failed
+    fn raise(self) -> ! {
+        panic::panic_any(self)
+    }
+}
 
-    while i < s.len() {
-        match bytes[i] {
-            b'{' => {
-                // Count the number of consecutive backslashes before '{'
-                let mut backslash_count = 0;
-                while i > 0 && bytes[i - 1 - backslash_count] == b'\\' && backslash_count + 1 < i {
-                    backslash_count += 1;
-                }
+// how problems get written to stderr
+#[derive(Clone, Copy, PartialEq, Default)]
+enum ErrorFormat {
+    #[default]
+    Text,
+    // one json object per line, see diagnostic_json
+    Json,
+}
 
-                // If the number of consecutive backslashes is even, count the brace
-                if backslash_count % 2 == 0 {
-                    left_brace_count += 1;
-                }
-            },
-            b'}' => {
-                // Count the number of consecutive backslashes before '}'
-                let mut backslash_count = 0;
-                while i > 0 && bytes[i - 1 - backslash_count] == b'\\' && backslash_count + 1 < i {
-                    backslash_count += 1;
-                }
 
-                // If the number of consecutive backslashes is even, count the brace
-                if backslash_count % 2 == 0 {
-                    right_brace_count += 1;
-                    if right_brace_count > left_brace_count {
-                        return false; // Not balanced
-                    }
-                }
-            },
-            _ => {}
+//CATEGORY CODES
+
+// same numbering as tex, these are the categories the processor cares about
+const CAT_ESCAPE: u8 = 0;
+const CAT_BEGIN_GROUP: u8 = 1;
+const CAT_END_GROUP: u8 = 2;
+const CAT_PARAM: u8 = 6;
+const CAT_LETTER: u8 = 11;
+const CAT_OTHER: u8 = 12;
+const CAT_COMMENT: u8 = 14;
+
+// category code of every ascii character, anything else is always "other"
+#[derive(Clone)]
+struct Catcodes {
+    codes: [u8; 128],
+}
+
+impl Default for Catcodes {
+    fn default() -> Catcodes {
+        let mut codes = [CAT_OTHER; 128];
+        for (c, code) in codes.iter_mut().enumerate() {
+            if (c as u8).is_ascii_alphanumeric() {
+                *code = CAT_LETTER;
+            }
         }
+        codes[b'\\' as usize] = CAT_ESCAPE;
+        codes[b'{' as usize] = CAT_BEGIN_GROUP;
+        codes[b'}' as usize] = CAT_END_GROUP;
+        codes[b'#' as usize] = CAT_PARAM;
+        codes[b'%' as usize] = CAT_COMMENT;
 
-        if left_brace_count > 0 && left_brace_count == right_brace_count {
-            // Found a balanced set of braces
-            return true;
+        Catcodes { codes }
+    }
+}
+
+impl Catcodes {
+    fn code(&self, c: char) -> u8 {
+        if c.is_ascii() { self.codes[c as usize] } else { CAT_OTHER }
+    }
+
+    fn is_escape(&self, c: char) -> bool {
+        self.code(c) == CAT_ESCAPE
+    }
+
+    fn is_begin_group(&self, c: char) -> bool {
+        self.code(c) == CAT_BEGIN_GROUP
+    }
+
+    fn is_end_group(&self, c: char) -> bool {
+        self.code(c) == CAT_END_GROUP
+    }
+
+    fn is_param(&self, c: char) -> bool {
+        self.code(c) == CAT_PARAM
+    }
+
+    fn is_comment(&self, c: char) -> bool {
+        self.code(c) == CAT_COMMENT
+    }
+
+    // what gets written out when an escape has to be reproduced, backslash if it still is one
+    fn escape_char(&self) -> char {
+        if self.is_escape('\\') {
+            return '\\';
+        }
+        (0..128u8).map(|b| b as char).find(|&c| self.is_escape(c)).unwrap_or('\\')
+    }
+
+    // letters, digits and whitespace keep their meaning since names and layout depend on them.
+    // 11 and 12 both just make the character ordinary
+    fn set(&mut self, c: char, code: u32) -> Result<(), String> {
+        if !c.is_ascii() || c.is_ascii_alphanumeric() || c.is_ascii_whitespace() {
+            return Err(format!("can't change the category of '{}'", c));
         }
 
-        i += 1;
+        let code = match code {
+            0 => CAT_ESCAPE,
+            1 => CAT_BEGIN_GROUP,
+            2 => CAT_END_GROUP,
+            6 => CAT_PARAM,
+            11 | 12 => CAT_OTHER,
+            14 => CAT_COMMENT,
+            _ => return Err(format!("unsupported category code {}", code)),
+        };
+
+        self.codes[c as usize] = code;
+        Ok(())
+    }
+}
+
+
+//helpers!!
+
+// the character starting at byte i of s. the state machines step through bytes,
+// but text they copy out has to stay whole characters
+fn char_at(s: &str, i: usize) -> char {
+    match s.get(i..).and_then(|rest| rest.chars().next()) {
+        Some(c) => c,
+        None => s.as_bytes().get(i).map_or('\0', |&b| b as char),
     }
+}
+
+// true if byte i of s opens a group
+fn begins_group(s: &str, i: usize, cat: &Catcodes) -> bool {
+    s.as_bytes().get(i).is_some_and(|&b| cat.is_begin_group(b as char))
+}
 
-    false // Not balanced
+// true if byte i of s closes one
+fn ends_group(s: &str, i: usize, cat: &Catcodes) -> bool {
+    s.as_bytes().get(i).is_some_and(|&b| cat.is_end_group(b as char))
 }
 
+// why the first group at or after some index doesn't close
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unbalanced {
+    // the text ends before any group starts
+    NoGroup,
+    // this opening brace is still open when the text ends
+    Unclosed(usize),
+    // this closing brace comes before any group was opened
+    Extra(usize),
+}
 
-fn brace_balance_end(s: &str, index: usize) -> usize {
+// the closing brace of the first group at or after index. an escape makes the
+// character after it ordinary, so \{ and \} don't count while \\{ does. when the
+// group never closes the innermost brace left open is the one reported
+fn match_group(s: &str, index: usize, cat: &Catcodes) -> Result<usize, Unbalanced> {
     let bytes = s.as_bytes();
-    let mut left_brace_count = 0;
-    let mut right_brace_count = 0;
+    let mut open = Vec::new();
     let mut i = index;
 
-    while i < s.len() {
-        let c = bytes[i];
+    while i < bytes.len() {
+        let c = bytes[i] as char;
 
-        if c == b'{' && (i == 0 || (bytes[i - 1] != b'\\' && bytes[i - 1] != b'%')) {
-            left_brace_count += 1;
-        } else if c == b'{' && bytes[i - 1] == b'\\' && i != 1 && bytes[i - 2] == b'\\' {
-            left_brace_count += 1;
-        } else if c == b'}' && (i == 0 || (bytes[i - 1] != b'\\' && bytes[i - 1] != b'%')) {
-            right_brace_count += 1;
-            if right_brace_count > left_brace_count {
-                return usize::MAX;
-            }
-            if left_brace_count == right_brace_count {
-                return i;
-            }
-        } else if c == b'}' && bytes[i - 1] == b'\\' && i != 1 && bytes[i - 2] == b'\\' {
-            right_brace_count += 1;
-            if right_brace_count > left_brace_count {
-                return usize::MAX;
+        if cat.is_escape(c) {
+            i += 1;
+        } else if cat.is_begin_group(c) {
+            open.push(i);
+        } else if cat.is_end_group(c) {
+            if open.pop().is_none() {
+                return Err(Unbalanced::Extra(i));
             }
-            if left_brace_count == right_brace_count {
-                return i;
+            if open.is_empty() {
+                return Ok(i);
             }
         }
 
         i += 1;
     }
 
-    return usize::MAX; // Indicating no balanced set was found
+    match open.pop() {
+        Some(brace) => Err(Unbalanced::Unclosed(brace)),
+        None => Err(Unbalanced::NoGroup),
+    }
+}
+
+// where processing picks up again after an error at i: past the group that
+// starts there, the end of the line when that group never closes, or i itself
+fn recovery_point(s: &str, i: usize, cat: &Catcodes) -> usize {
+    if !begins_group(s, i, cat) {
+        return i.min(s.len());
+    }
+
+    if let Ok(end) = match_group(s, i, cat) {
+        return end + 1;
+    }
+
+    match s[i..].find('\n') {
+        Some(newline) => i + newline + 1,
+        None => s.len(),
+    }
 }
 
+#[derive(Clone)]
 struct MacroNode {
     name: String,
     value: String,
+    // tex style parameter text like (#1,#2), None for the usual single {argument}
+    params: Option<String>,
+    location: Option<Location>,
+    // expansions and tests of the macro, --check reports definitions nothing used
+    uses: usize,
     next: Option<Box<MacroNode>>,
 }
 
 impl MacroNode {
-    fn new(name: &str, value: &str) -> Box<MacroNode> {
+    fn new(name: &str, value: &str, params: Option<String>, location: Option<Location>) -> Box<MacroNode> {
         Box::new(MacroNode {
             name: name.to_string(),
             value: value.to_string(),
+            params,
+            location,
+            uses: 0,
             next: None,
         })
     }
@@ -135,17 +309,20 @@ fn macro_exists(head: &Option<Box<MacroNode>>, name: &str) -> bool {
     false // Reached the end of the list without finding the macro.
 }
 
-fn add_or_update_macro(head: &mut Option<Box<MacroNode>>, name: &str, value: &str) {
+fn add_or_update_macro(head: &mut Option<Box<MacroNode>>, name: &str, value: &str, params: Option<String>, location: Option<Location>) {
     let mut current = head;
     while let Some(ref mut node) = current {
         if node.name == name {
             node.value = value.to_string();
+            node.params = params;
+            node.location = location;
+            node.uses = 0;
             return;
         }
         current = &mut node.next;
     }
 
-    let new_node = MacroNode::new(name, value);
+    let new_node = MacroNode::new(name, value, params, location);
     *current = Some(new_node);
 }
 
@@ -173,926 +350,4896 @@ fn find_macro_value(head: &Option<Box<MacroNode>>, name: &str) -> Option<String>
     None
 }
 
-fn process_comments(input: &str) -> String {
-    let mut output = String::new();
-    let mut backslash_count = 0;
-    let mut in_comment = false;
+fn find_macro<'a>(head: &'a Option<Box<MacroNode>>, name: &str) -> Option<&'a MacroNode> {
+    let mut current = head;
+    while let Some(ref node) = current {
+        if node.name == name {
+            return Some(node);
+        }
+        current = &node.next;
+    }
+    None
+}
 
-    let mut after_comment = false;
+fn mark_macro_used(head: &mut Option<Box<MacroNode>>, name: &str) {
+    let mut current = head;
+    while let Some(ref mut node) = current {
+        if node.name == name {
+            node.uses += 1;
+            return;
+        }
+        current = &mut node.next;
+    }
+}
 
-    let mut last_char_was_backslash = false;
+// one line summary of a macro in the style of tex's \show
+fn describe_macro(node: &MacroNode) -> String {
+    let params = node.params.as_deref().unwrap_or("");
+    let mut line = format!("\\{}=macro:{}->{}", node.name, params, node.value);
+    if let Some(location) = &node.location {
+        line.push_str(&format!(" (defined at {})", location));
+    }
+    line
+}
 
-    for c in input.chars() {
 
+//SOURCE TRACKING
 
-        if c == '\\' {
-            //FIXED
-            if !in_comment
-            {
-                backslash_count += 1;
-                last_char_was_backslash = true;
-            }
-        } else {
-            if c == '%' {
-                if backslash_count % 2 == 1 {
-                    // Odd number of backslashes before '%'
-                    output.push('\\');
+#[derive(Clone)]
+struct Location {
+    file: String,
+    line: usize,
+    col: usize,
+    // which of the context's files it is in. names can repeat (a/x.tex and b/x.tex
+    // both included as x.tex, every repl entry is <repl>) so this is what to go by.
+    // None when the place isn't in one of them, like a definition from a saved state
+    source: Option<usize>,
+}
 
-                    for _b in 0..((backslash_count - 1)/2)
-                    {
-                        output.push('\\');
-                    }
-                    
-                    if !in_comment {
-                        // if not in a comment, treat '%' as literal because it's escaped
-                        output.push('%');
-                    } else if last_char_was_backslash {
-                        // in comment and '%' is escaped, do not add it to output
-                        // remove the incorrectly added backslash in previous iteration
-                        if output.ends_with('\\') {
-                            output.pop();
-                        }
-                    }
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
 
-                } else {
-                    // Even number of backslashes, or no backslashes
-                    if !in_comment {
-                        in_comment = true; // Start comment mode only if not already in comment
-                    } else if last_char_was_backslash {
-                        // In comment and '%' is escaped, ensure it's not added to output
-                    }
+// a file (or stdin) that was read in, kept around so positions can be mapped back
+struct SourceFile {
+    name: String,
+    // where it was read from, None for stdin
+    path: Option<PathBuf>,
+    // the same path with links and .. resolved, used to recognise a file read twice
+    canonical: Option<PathBuf>,
+    // the file whose \include read this one in
+    included_from: Option<usize>,
+    raw: String,
+    // text after comment removal, this is what process sees
+    text: String,
+    // for every byte of text, the byte in raw it came from
+    offsets: Vec<usize>,
+    line_starts: Vec<usize>,
+}
 
-                    for _b in 0..(backslash_count)
-                    {
-                        output.push('\\');
-                    }
+impl SourceFile {
+    fn new(name: &str, raw: String, cat: &Catcodes) -> SourceFile {
+        let (text, offsets) = process_comments(&raw, cat);
 
-                }
-                backslash_count = 0; // reset backslash count after handling '%'
-                last_char_was_backslash = false; // reset last character
-            } else {
+        let mut line_starts = vec![0];
+        for (k, b) in raw.bytes().enumerate() {
+            if b == b'\n' {
+                line_starts.push(k + 1);
+            }
+        }
 
-                if backslash_count > 0 {
-                    // handle backslashes before a normal character
-                    output.push_str(&"\\".repeat(backslash_count));
-                    backslash_count = 0;
-                }
+        SourceFile {
+            name: name.to_string(),
+            path: None,
+            canonical: None,
+            included_from: None,
+            raw,
+            text,
+            offsets,
+            line_starts,
+        }
+    }
 
-                if after_comment {
-                    if c == '\t' || c == ' '
-                    {
-                        continue;
-                    }
-                    else
-                    {
-                        after_comment = false;
-                    }
-                }
+    fn location(&self, offset: usize) -> Location {
+        let raw_offset = self.offsets.get(offset).copied().unwrap_or(self.raw.len());
+        let line = self.line_starts.partition_point(|&start| start <= raw_offset);
+        let line_start = self.line_starts[line - 1];
 
-                if !in_comment || (c == '\n' && last_char_was_backslash) {
-                    output.push(c); 
-                }
+        Location {
+            file: self.name.clone(),
+            line,
+            col: self.raw[line_start..raw_offset].chars().count() + 1,
+            source: None,
+        }
+    }
+}
 
-                if c == '\n' {
+// a run of bytes of the text being processed. either it is still the text of a
+// file (and offset moves along as it gets consumed) or it was produced by an
+// expansion, in which case offset is the place in the file that expanded it.
+// depth counts how many expansions deep the text is, file text being 0
+#[derive(Clone)]
+struct Segment {
+    len: usize,
+    file: Option<usize>,
+    offset: usize,
+    generated: bool,
+    depth: usize,
+    // the command whose expansion produced the text
+    expansion: Option<String>,
+}
 
-                    if in_comment
-                    {
-                        after_comment = true;
-                    }
+// describes where each byte of the current input came from, front to back.
+// every time process re-feeds itself with "new text + rest of input" the layout
+// gets the same treatment so the two always stay the same length
+#[derive(Clone, Default)]
+struct Layout {
+    segments: Vec<Segment>,
+}
 
-                    in_comment = false; // end comment mode on newline
+impl Layout {
+    fn file(file: usize, len: usize) -> Layout {
+        Layout {
+            segments: vec![Segment { len, file: Some(file), offset: 0, generated: false, depth: 0, expansion: None }],
+        }
+    }
+
+    // text made up on the spot, attributed to whatever produced it
+    fn generated(len: usize, origin: Option<(usize, usize)>, depth: usize, expansion: &str) -> Layout {
+        let (file, offset) = match origin {
+            Some((file, offset)) => (Some(file), offset),
+            None => (None, 0),
+        };
+
+        Layout {
+            segments: vec![Segment { len, file, offset, generated: true, depth, expansion: Some(expansion.to_string()) }],
+        }
+    }
+
+    // drop the first n bytes
+    fn consume(&mut self, mut n: usize) {
+        while n > 0 && !self.segments.is_empty() {
+            let first = &mut self.segments[0];
+            if first.len <= n {
+                n -= first.len;
+                self.segments.remove(0);
+            } else {
+                first.len -= n;
+                if !first.generated {
+                    first.offset += n;
                 }
+                n = 0;
+            }
+        }
+    }
 
-                last_char_was_backslash = false; // reset last character
+    fn prepend(&mut self, front: Layout) {
+        let rest = std::mem::take(&mut self.segments);
+        self.segments = front.segments.into_iter().filter(|seg| seg.len > 0).collect();
+        self.segments.extend(rest);
+    }
+
+    // the layout of bytes start..end
+    fn slice(&self, start: usize, end: usize) -> Layout {
+        let mut sliced = self.clone();
+        sliced.consume(start);
+
+        let mut remaining = end - start;
+        let mut keep = 0;
+        while keep < sliced.segments.len() && remaining > 0 {
+            let seg = &mut sliced.segments[keep];
+            seg.len = seg.len.min(remaining);
+            remaining -= seg.len;
+            keep += 1;
+        }
+        sliced.segments.truncate(keep);
+        sliced
+    }
+
+    // the segment holding byte i and where that segment starts
+    fn segment_at(&self, i: usize) -> Option<(&Segment, usize)> {
+        let mut start = 0;
+        for seg in &self.segments {
+            if i < start + seg.len {
+                return Some((seg, start));
             }
+            start += seg.len;
         }
+        None
     }
 
-    // handle remaining backslashes at the end of input if there are  any
-    if backslash_count > 0 {
-        output.push_str(&"\\".repeat(backslash_count));
+    // (file, offset into its text) for byte i
+    fn origin_at(&self, i: usize) -> Option<(usize, usize)> {
+        let (seg, start) = self.segment_at(i)?;
+        let offset = if seg.generated { seg.offset } else { seg.offset + (i - start) };
+        seg.file.map(|file| (file, offset))
     }
 
-    output
+    fn depth_at(&self, i: usize) -> usize {
+        self.segment_at(i).map_or(0, |(seg, _)| seg.depth)
+    }
 }
 
+// output from position out on (counted over the whole output) came from here,
+// up to where the next mapping starts
+struct Mapping {
+    out: usize,
+    file: Option<usize>,
+    offset: usize,
+    generated: bool,
+    expansion: Option<String>,
+}
 
-
-#[derive(PartialEq)]
-enum StateBks {
-    InitialBks,
-    EscapeBks,
+impl Mapping {
+    // whether the text at out carries straight on from this mapping
+    fn continues(&self, next: &Mapping) -> bool {
+        if self.file != next.file || self.generated != next.generated || self.expansion != next.expansion {
+            return false;
+        }
+        if self.generated {
+            self.offset == next.offset
+        } else {
+            self.offset + (next.out - self.out) == next.offset
+        }
+    }
 }
 
-//PROCESS ESCAPES
-fn process_backslashes(input: &str, _head: &mut Option<Box<MacroNode>>) -> String {
+//DIAGNOSTICS
 
-    let mut current_state = StateBks::InitialBks;
-    let mut output = String::new();
-    let mut backslash_count = 0;
+#[derive(Clone, Copy, PartialEq)]
+enum Severity {
+    Warning,
+    Error(ErrorKind),
+}
 
-    let _stop = 0;
+// a problem that gets reported once processing is over
+struct Diagnostic {
+    severity: Severity,
+    // short fixed name for the kind of problem, for tools
+    code: &'static str,
+    location: Option<Location>,
+    // where the problem stops, when that is known and in the same file
+    end: Option<Location>,
+    message: String,
+    // how the text got there: the expansion it is in and the includes that read it
+    notes: Vec<String>,
+    // said under the caret at location
+    label: Option<String>,
+    // another place worth pointing at, in the same file
+    secondary: Option<(Location, String)>,
+    // a suggestion for fixing it
+    help: Option<String>,
+    // the lines of the file the places above are on, by line number. kept with the
+    // diagnostic since the files may be gone by the time it's printed
+    source: Vec<(usize, String)>,
+}
 
-    //DO I NEED THIS
-    //probably
-    let bytes = input.as_bytes(); // convert the string to a byte slice
+impl Diagnostic {
+    fn new(severity: Severity, code: &'static str, location: Option<Location>, message: String) -> Diagnostic {
+        Diagnostic {
+            severity,
+            code,
+            location,
+            end: None,
+            message,
+            notes: Vec::new(),
+            label: None,
+            secondary: None,
+            help: None,
+            source: Vec::new(),
+        }
+    }
+
+    // rustc style: the message, where it is, the source line with a caret under the
+    // place, then notes and help
+    fn render(&self, color: bool) -> String {
+        let paint = |code: &str, text: &str| if color { format!("\x1b[{}m{}\x1b[0m", code, text) } else { text.to_string() };
+        let (label, accent) = match self.severity {
+            Severity::Warning => ("warning", "1;33"),
+            Severity::Error(_) => ("error", "1;31"),
+        };
+
+        let mut out = format!("{}: {}", paint(accent, label), paint("1", &self.message));
+
+        let location = match &self.location {
+            Some(location) => location,
+            None => return out,
+        };
+
+        // (line, column, width, marker, label), the primary place first
+        let mut marks = Vec::new();
+        let width = match &self.end {
+            Some(end) if end.line == location.line && end.col > location.col => end.col - location.col,
+            _ => 1,
+        };
+        marks.push((location.line, location.col, width, '^', self.label.clone().unwrap_or_default()));
+        if let Some((place, text)) = &self.secondary {
+            if place.source == location.source {
+                marks.push((place.line, place.col, 1, '-', text.clone()));
+            }
+        }
 
+        let mut lines: Vec<usize> = marks.iter().map(|mark| mark.0).collect();
+        lines.sort();
+        lines.dedup();
 
-    let mut i = 0usize;
+        let gutter = lines.last().map_or(1, |line| line.to_string().len());
+        let pipe = paint("1;34", "|");
+        out.push_str(&format!("\n{}{} {}", " ".repeat(gutter), paint("1;34", "-->"), location));
 
-    while i < input.len() {
-        let c = bytes[i] as char;
+        if lines.iter().all(|line| self.source.iter().any(|(number, _)| number == line)) {
+            out.push_str(&format!("\n{} {}", " ".repeat(gutter), pipe));
 
-        match current_state
-        {
-            StateBks::InitialBks => {
-                backslash_count = 0;
+            for line in lines {
+                let text = &self.source.iter().find(|(number, _)| *number == line).unwrap().1;
+                out.push_str(&format!("\n{} {} {}", paint("1;34", &format!("{:>width$}", line, width = gutter)), pipe, text.replace('\t', "    ")));
 
-                if c == '\\'
-                {
-                    current_state = StateBks::EscapeBks;
-                    backslash_count += 1;
-                }
-                else
-                {
-                    output.push(c);
+                for &(_, col, width, marker, ref text_label) in marks.iter().filter(|mark| mark.0 == line) {
+                    // tabs were widened above, the markers have to move the same way
+                    let indent: usize = text.chars().take(col - 1).map(|c| if c == '\t' { 4 } else { 1 }).sum();
+                    let marker_accent = if marker == '^' { accent } else { "1;34" };
+                    let underline = paint(marker_accent, &format!("{} {}", marker.to_string().repeat(width), text_label));
+                    out.push_str(&format!("\n{} {} {}{}", " ".repeat(gutter), pipe, " ".repeat(indent), underline.trim_end()));
                 }
-
             }
+        }
 
-            StateBks::EscapeBks => {
-                if c == '\\' || c == '#' || c == '%' || c == '{' || c == '}'
-                {
-                    if c == '\\'
-                    {
-                        backslash_count += 1;
+        for note in &self.notes {
+            out.push_str(&format!("\n{} {} note: {}", " ".repeat(gutter), paint("1;34", "="), note));
+        }
+        if let Some(help) = &self.help {
+            out.push_str(&format!("\n{} {} {}: {}", " ".repeat(gutter), paint("1;34", "="), paint("1", "help"), help));
+        }
 
+        out
+    }
+}
 
-                        for _b in 0..(backslash_count / 2)
-                        {
-                            output.push('\\');
-                        }
+// diagnostics are only colored for someone reading them in a terminal. they go to
+// stderr, so that is the one that counts: output piped to a file still gets them colored
+fn color_stderr() -> bool {
+    io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
 
-                        for _b in 0..(backslash_count % 2)
-                        {
-                            output.push('\\');
-                        }
-                    }
-                    else
-                    {
-                        output.push(c);
-                    }
+// how many single character edits turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
 
-                    current_state = StateBks::InitialBks;
-                }
-                else if !c.is_alphanumeric()
-                {
-                    //preserve \s
-                    output.push('\\');
-                    output.push(c);
+    for (k, ca) in a.chars().enumerate() {
+        let mut current = vec![k + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
 
-                    current_state = StateBks::InitialBks;
-                }
-                //if even backslashes then macro is useless
-                else if c.is_alphanumeric() && backslash_count % 2 == 0
-                {
-                    for _b in 0..(backslash_count / 2)
-                    {
-                        output.push('\\');
-                    }
+    previous[b.len()]
+}
 
-                    output.push(c);
+// the commands that exist without being defined
+const BUILTINS: [&str; 13] = [
+    "def", "undef", "if", "ifdef", "include", "includeonce", "expandafter",
+    "meaning", "showmacro", "catcode", "listmacros", "tracingon", "tracingoff",
+];
 
-                    current_state = StateBks::InitialBks;
+// the closest defined macro or builtin to a misspelled name, if any is close enough
+fn suggest_macro(name: &str, head: &Option<Box<MacroNode>>) -> Option<String> {
+    let mut candidates: Vec<&str> = BUILTINS.to_vec();
+    let mut current = head;
+    while let Some(ref node) = current {
+        candidates.push(&node.name);
+        current = &node.next;
+    }
 
-                    //macro useless, just output as plaintext
-                }
-            }
+    let allowed = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, candidate)| distance <= allowed && candidate != name)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+// definitions made in the input that nothing ever used. -D ones and ones from
+// a saved state don't count
+fn unused_macros(head: &Option<Box<MacroNode>>, ctx: &Context) -> Vec<Diagnostic> {
+    let mut unused = Vec::new();
+    let mut current = head;
+    while let Some(ref node) = current {
+        let from_input = node.location.as_ref().is_some_and(|location| location.source.is_some());
+        if node.uses == 0 && from_input {
+            let mut diagnostic = Diagnostic::new(
+                Severity::Warning,
+                "unused-macro",
+                node.location.clone(),
+                format!("\\{} is defined but never used", node.name),
+            );
+            diagnostic.source = node.location.iter().filter_map(|location| ctx.source_line(location)).collect();
+            unused.push(diagnostic);
         }
+        current = &node.next;
+    }
+    unused
+}
 
-        i += 1;
+
+//FILE ACCESS
+
+// where input files come from. everything that reads an input goes through
+// this, so files can be served from memory or anywhere else instead of the disk
+trait FileResolver {
+    fn is_file(&self, path: &Path) -> bool;
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    // a path naming the same file that is the same whichever way the file was reached
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+// contexts read from the real filesystem unless told otherwise
+impl Default for Box<dyn FileResolver> {
+    fn default() -> Box<dyn FileResolver> {
+        Box::new(FsResolver)
     }
-    return output;
 }
 
+struct FsResolver;
 
-fn replace_hash_with_arg(macro_value: &str, arg: &str) -> String {
-    let mut result = String::new();
-    let mut chars = macro_value.chars().peekable();
-    let mut backslash_count = 0;
+impl FileResolver for FsResolver {
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
 
-    while let Some(c) = chars.next() {
-        match c {
-            '#' => {
-                if backslash_count % 2 == 0 {
-                    // if there are an even number of backslashes (including 0), replace '#' with arg
-                    // correctly handle even backslashes by preserving (!!!!) them
-                    for _ in 0..backslash_count {
-                        result.push('\\');
-                    }
-                    result.push_str(arg);
-                } else {
-                    // if there's an odd number of backslashes, it means '#' is escaped
-                    // preserve all backslashes
-                    for _ in 0..backslash_count {
-                        result.push('\\');
-                    }
-                    // add '#' bc the last backslash escapes it
-                    result.push('#');
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+}
+
+// files kept in memory by path, paths are compared after normalize_path
+#[derive(Clone, Default)]
+struct MemoryResolver {
+    files: HashMap<PathBuf, String>,
+}
+
+impl MemoryResolver {
+    fn insert(&mut self, path: &str, contents: &str) {
+        self.files.insert(normalize_path(Path::new(path)), contents.to_string());
+    }
+
+    fn remove(&mut self, path: &str) {
+        self.files.remove(&normalize_path(Path::new(path)));
+    }
+}
+
+impl FileResolver for MemoryResolver {
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(&normalize_path(path))
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        match self.files.get(&normalize_path(path)) {
+            Some(contents) => Ok(contents.clone()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no such file in memory")),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(normalize_path(path))
+    }
+}
+
+// what an editor sees: the documents it has open, as they are in the editor,
+// and the disk for everything else
+struct EditorResolver {
+    documents: MemoryResolver,
+}
+
+impl FileResolver for EditorResolver {
+    fn is_file(&self, path: &Path) -> bool {
+        self.documents.is_file(path) || FsResolver.is_file(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        self.documents.read_to_string(path).or_else(|_| FsResolver.read_to_string(path))
+    }
+
+    // a document that also exists on disk has to be recognised when an include reaches it there
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        FsResolver.canonicalize(path).or_else(|_| self.documents.canonicalize(path))
+    }
+}
+
+// takes out . and resolves .. without looking at the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
                 }
-                backslash_count = 0; // reset backslash count
+                // nothing above the root
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
             },
-            '\\' => {
-                backslash_count += 1;
+            other => normalized.push(other),
+        }
+    }
+
+    normalized
+}
+
+// keeps a trace entry on a single line
+fn one_line(s: &str) -> String {
+    s.replace('\n', "\\n").replace('\t', "\\t")
+}
+
+// bounds on the work done for a document, so untrusted input can't run away.
+// None is no bound
+#[derive(Clone, Default)]
+struct Limits {
+    // how many expansions deep text may be, see Segment
+    max_depth: Option<usize>,
+    // commands run in total
+    max_steps: Option<usize>,
+    max_output: Option<usize>,
+    // length of an include chain
+    max_include_depth: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+// used unless --max-depth says otherwise, a macro that uses itself stops here instead of running out of stack
+const DEFAULT_MAX_DEPTH: usize = 10000;
+
+// stack for the thread doing the processing. every expansion is another call to process
+const ENGINE_STACK_SIZE: usize = 1 << 30;
+
+// everything process needs besides the macro list
+#[derive(Default)]
+struct Context {
+    files: Vec<SourceFile>,
+    layout: Layout,
+    catcodes: Catcodes,
+    // expansion tracing, goes to stderr unless a trace file was given
+    tracing: bool,
+    trace_file: Option<Box<dyn Write>>,
+    // -I directories followed by TEXPROC_PATH, tried in order when a file isn't found as given
+    search_paths: Vec<PathBuf>,
+    resolver: Box<dyn FileResolver>,
+    // when set, includes have to stay inside this (canonical) directory
+    sandbox: Option<PathBuf>,
+    limits: Limits,
+    // commands run so far and when processing started, for the limits
+    steps: usize,
+    started: Option<Instant>,
+    // output of the process calls further up that are waiting on this one
+    emitted: usize,
+    // groups the text has opened and not closed yet, carried across the process
+    // calls that hand the rest of the text on to each other
+    open_groups: usize,
+    // keep going after syntax and semantic errors and includes that fail, they are
+    // collected in diagnostics instead. running out of steps, output, depth or time
+    // still stops everything
+    recover: bool,
+    diagnostics: Vec<Diagnostic>,
+    // unknown commands are written out as they are instead of being errors. the ones
+    // in passthrough go quietly, the rest get a warning the first time they're seen
+    lenient: bool,
+    passthrough: HashSet<String>,
+    warned: HashSet<String>,
+    // where the output came from, for --source-map. in the order the output was made
+    mappings: Option<Vec<Mapping>>,
+    error_format: ErrorFormat,
+}
+
+impl Context {
+    // registers text that was read in and returns its id
+    fn add_source(&mut self, name: &str, path: Option<PathBuf>, raw: String) -> usize {
+        let mut source = SourceFile::new(name, raw, &self.catcodes);
+        source.path = path;
+        self.files.push(source);
+        self.files.len() - 1
+    }
+
+    fn already_included(&self, path: &Path) -> bool {
+        let canonical = canonical_path(path, self);
+        self.files.iter().any(|file| file.canonical.as_ref() == Some(&canonical))
+    }
+
+    // names of the files from the top level input down to file, following the includes
+    fn include_chain(&self, file: usize) -> Vec<usize> {
+        let mut chain = vec![file];
+        while let Some(parent) = self.files[*chain.last().unwrap()].included_from {
+            chain.push(parent);
+        }
+        chain.reverse();
+        chain
+    }
+
+    // directory of the file byte i of the input came from
+    fn dir_at(&self, i: usize) -> Option<PathBuf> {
+        let (file, _) = self.layout.origin_at(i)?;
+        self.files[file].path.as_ref()?.parent().map(Path::to_path_buf)
+    }
+
+    fn location_at(&self, i: usize) -> Option<Location> {
+        self.layout.origin_at(i).map(|(file, offset)| Location { source: Some(file), ..self.files[file].location(offset) })
+    }
+
+    // output from out on comes from byte i of the input
+    fn map_output(&mut self, out: usize, i: usize) {
+        let mapping = match self.layout.segment_at(i) {
+            Some((seg, start)) => Mapping {
+                out,
+                file: seg.file,
+                offset: if seg.generated { seg.offset } else { seg.offset + (i - start) },
+                generated: seg.generated,
+                expansion: seg.expansion.clone(),
             },
-            _ => {
-                // every backslash should be preserved!! will go through other machine later
-                for _ in 0..backslash_count {
-                    result.push('\\');
+            None => return,
+        };
+
+        let mappings = match &mut self.mappings {
+            Some(mappings) => mappings,
+            None => return,
+        };
+
+        // a mapping nothing was written for yet gets replaced
+        if mappings.last().is_some_and(|last| last.out == out) {
+            mappings.pop();
+        }
+        if mappings.last().is_some_and(|last| last.continues(&mapping)) {
+            return;
+        }
+        mappings.push(mapping);
+    }
+
+    // how the text at i came to be processed, innermost first
+    fn notes_at(&self, i: usize) -> Vec<String> {
+        let mut notes = Vec::new();
+
+        if let Some((seg, _)) = self.layout.segment_at(i) {
+            if let Some(expansion) = &seg.expansion {
+                notes.push(format!("in the expansion of \\{}", expansion));
+            }
+        }
+
+        if let Some((file, _)) = self.layout.origin_at(i) {
+            let chain = self.include_chain(file);
+            for pair in chain.windows(2).rev() {
+                notes.push(format!("{} was included from {}", self.files[pair[1]].name, self.files[pair[0]].name));
+            }
+        }
+
+        notes
+    }
+
+    // the line of its file a location is on, with its number
+    fn source_line(&self, location: &Location) -> Option<(usize, String)> {
+        let file = self.files.get(location.source?)?;
+        let text = file.raw.lines().nth(location.line - 1)?;
+        Some((location.line, text.to_string()))
+    }
+
+    // the line the text at i came from, with its number
+    fn source_line_at(&self, i: usize) -> Option<(usize, String)> {
+        let (file, offset) = self.layout.origin_at(i)?;
+        let line = self.files[file].location(offset).line;
+        let text = self.files[file].raw.lines().nth(line - 1)?;
+        Some((line, text.to_string()))
+    }
+
+    // a problem with the command whose escape is at start, noticed at at
+    fn diagnostic_at(&self, start: usize, at: usize, severity: Severity, code: &'static str, message: String) -> Diagnostic {
+        let location = self.location_at(start);
+        let end = self.location_at(at).filter(|end| {
+            location.as_ref().is_some_and(|start| start.source == end.source && (start.line, start.col) <= (end.line, end.col))
+        });
+
+        let mut diagnostic = Diagnostic::new(severity, code, location, message);
+        diagnostic.end = end;
+        diagnostic.notes = self.notes_at(start);
+        diagnostic.source = self.source_line_at(start).into_iter().collect();
+        diagnostic
+    }
+
+    // the group the command whose escape is at start reads from i doesn't close. the
+    // diagnostic points at the brace that is to blame
+    fn unbalanced_at(&self, start: usize, i: usize, unbalanced: Unbalanced, kind: ErrorKind, message: String) -> Diagnostic {
+        let (at, label) = match unbalanced {
+            Unbalanced::Unclosed(brace) => (brace, "this group is never closed"),
+            Unbalanced::Extra(brace) => (brace, "this closes a group that was never opened"),
+            Unbalanced::NoGroup => return self.diagnostic_at(start, i, Severity::Error(kind), kind.code(), message),
+        };
+        let mut diagnostic = self.diagnostic_at(at, at, Severity::Error(kind), kind.code(), message);
+        diagnostic.label = Some(label.to_string());
+
+        // a brace found on its own is the whole problem, there's no command to show
+        if let Some(command) = self.location_at(start).filter(|_| start != at) {
+            if let Some(line) = self.source_line_at(start) {
+                if !diagnostic.source.contains(&line) {
+                    diagnostic.source.push(line);
                 }
-                backslash_count = 0; // reset backslash count
-                result.push(c); 
             }
+            diagnostic.secondary = Some((command, "in this command".to_string()));
         }
+        diagnostic
     }
 
-    // handle any remaining backslashes at the end of string
-    for _ in 0..backslash_count {
-        result.push('\\');
+    // the closing brace at i ends the innermost open group. one that has no group to
+    // end is copied through like any other character, but with a warning
+    fn close_group(&mut self, i: usize) {
+        if self.open_groups > 0 {
+            self.open_groups -= 1;
+            return;
+        }
+
+        let message = "closing brace without a group to close passed through".to_string();
+        let mut diagnostic = self.unbalanced_at(i, i, Unbalanced::Extra(i), ErrorKind::Syntax, message);
+        diagnostic.severity = Severity::Warning;
+        diagnostic.code = "unmatched-brace";
+        self.diagnostics.push(diagnostic);
     }
 
-    result
-}
+    // counts the command whose escape is at i and checks the limits that depend on it
+    fn step(&mut self, i: usize) {
+        self.steps += 1;
 
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                self.exceeded(i, format!("limit exceeded: more than {} expansion steps", max));
+            }
+        }
 
+        if let Some(max) = self.limits.max_depth {
+            if self.layout.depth_at(i) > max {
+                self.exceeded(i, format!("limit exceeded: expansions nested more than {} deep", max));
+            }
+        }
 
-#[derive(PartialEq)]
-enum State {
-    Initial,
-    Escape,
-    GenMacro,
-    Def,
-    UserDef,
-    UnDef,
-    If,
-    IfDef,
-    Include,
-    ExpandAfter
-}
+        if let (Some(timeout), Some(started)) = (self.limits.timeout, self.started) {
+            if started.elapsed() > timeout {
+                self.exceeded(i, format!("limit exceeded: processing took longer than {:.3}s", timeout.as_secs_f64()));
+            }
+        }
+    }
+
+    // stops everything, it ran out of a limit at the command whose escape is at i
+    fn exceeded(&self, i: usize, message: String) -> ! {
+        let diagnostic = self.diagnostic_at(i, i, Severity::Error(ErrorKind::Limit), ErrorKind::Limit.code(), message);
+        Failure { kind: ErrorKind::Limit, diagnostic, reported: false }.raise()
+    }
 
-fn process(input: &str, head: &mut Option<Box<MacroNode>>, initial_string: &str) -> String {
+    // logs one expansion step of the command whose escape is at i
+    fn trace(&mut self, i: usize, name: &str, args: &str, result: &str) {
+        if !self.tracing {
+            return;
+        }
 
-    let mut current_state = State::Initial;
+        let location = self.location_at(i).map_or("<unknown>".to_string(), |l| l.to_string());
+        let line = format!(
+            "{}: [{}] \\{}{} -> {}",
+            location,
+            self.layout.depth_at(i),
+            name,
+            one_line(args),
+            one_line(result)
+        );
+
+        match &mut self.trace_file {
+            Some(file) => {
+                let _ = writeln!(file, "{}", line);
+            }
+            None => eprintln!("{}", line),
+        }
+    }
+}
+
+// comments only get stripped when text is read in, so a comment character set
+// with \catcode applies to files read after it
+fn process_comments(input: &str, cat: &Catcodes) -> (String, Vec<usize>) {
     let mut output = String::new();
+    let mut offsets = Vec::new();
     let mut backslash_count = 0;
+    let mut backslash_start = 0;
+    let mut in_comment = false;
 
+    let mut after_comment = false;
 
-    let _temp = String::new();
+    let mut last_char_was_backslash = false;
 
-    let mut arg1 = String::new();
-    let mut arg2 = String::new();
-    let mut arg3 = String::new();
+    let escape = cat.escape_char();
 
-    let mut argspecial = String::new();
+    for (k, c) in input.char_indices() {
 
-    let mut stop;
 
-    let bytes = input.as_bytes(); 
+        if cat.is_escape(c) {
+            //FIXED
+            if !in_comment
+            {
+                if backslash_count == 0 {
+                    backslash_start = k;
+                }
+                backslash_count += 1;
+                last_char_was_backslash = true;
+            }
+        } else {
+            if cat.is_comment(c) {
+                if backslash_count % 2 == 1 {
+                    // Odd number of backslashes before '%'
+                    push_backslashes(&mut output, &mut offsets, escape, 1 + (backslash_count - 1) / 2, backslash_start);
+                    
+                    if !in_comment {
+                        // if not in a comment, treat '%' as literal because it's escaped
+                        output.push(c);
+                        offsets.push(k);
+                    } else if last_char_was_backslash {
+                        // in comment and '%' is escaped, do not add it to output
+                        // remove the incorrectly added backslash in previous iteration
+                        if output.ends_with(escape) {
+                            output.pop();
+                            offsets.pop();
+                        }
+                    }
 
-    let mut i = 0usize;
+                } else {
+                    // Even number of backslashes, or no backslashes
+                    if !in_comment {
+                        in_comment = true; // Start comment mode only if not already in comment
+                    } else if last_char_was_backslash {
+                        // In comment and '%' is escaped, ensure it's not added to output
+                    }
 
-    while i < input.len() {
-        let c = bytes[i] as char;
+                    push_backslashes(&mut output, &mut offsets, escape, backslash_count, backslash_start);
 
-        match current_state
-        {
-            State::Initial => {
-                backslash_count = 0;
+                }
+                backslash_count = 0; // reset backslash count after handling '%'
+                last_char_was_backslash = false; // reset last character
+            } else {
 
-                if c == '\\'
-                {
-                    current_state = State::Escape;
-                    backslash_count += 1;
+                if backslash_count > 0 {
+                    // handle backslashes before a normal character
+                    push_backslashes(&mut output, &mut offsets, escape, backslash_count, backslash_start);
+                    backslash_count = 0;
                 }
-                else
-                {
-                    output.push(c);
+
+                if after_comment {
+                    if c == '\t' || c == ' '
+                    {
+                        continue;
+                    }
+                    else
+                    {
+                        after_comment = false;
+                    }
                 }
 
-            }
+                if !in_comment || (c == '\n' && last_char_was_backslash) {
+                    output.push(c); 
+                    offsets.extend(std::iter::repeat_n(k, c.len_utf8()));
+                }
 
-            State::Escape => {
-                if c == '\\'
-                {
-                    if backslash_count == 1
+                if c == '\n' {
+
+                    if in_comment
                     {
-                        output.push('\\');
+                        after_comment = true;
                     }
 
-                    backslash_count += 1;
+                    in_comment = false; // end comment mode on newline
+                }
+
+                last_char_was_backslash = false; // reset last character
+            }
+        }
+    }
+
+    // handle remaining backslashes at the end of input if there are  any
+    if backslash_count > 0 {
+        push_backslashes(&mut output, &mut offsets, escape, backslash_count, backslash_start);
+    }
+
+    (output, offsets)
+}
+
+// pushes count backslashes that came from the run starting at start
+fn push_backslashes(output: &mut String, offsets: &mut Vec<usize>, escape: char, count: usize, start: usize) {
+    for b in 0..count {
+        output.push(escape);
+        offsets.push(start + b);
+    }
+}
 
-                    output.push('\\');
 
-                    current_state = State::Escape;
+
+#[derive(PartialEq)]
+enum StateBks {
+    InitialBks,
+    EscapeBks,
+}
+
+//PROCESS ESCAPES
+// also gives, for every byte of the result, the byte of input it came from
+fn process_backslashes(input: &str, _head: &mut Option<Box<MacroNode>>, cat: &Catcodes) -> (String, Vec<usize>) {
+
+    let mut current_state = StateBks::InitialBks;
+    let mut output = String::new();
+    let mut offsets = Vec::new();
+    let mut backslash_count = 0;
+
+    let escape = cat.escape_char();
+
+    let _stop = 0;
+
+    let mut i = 0usize;
+
+    while i < input.len() {
+        let c = char_at(input, i);
+
+        match current_state
+        {
+            StateBks::InitialBks => {
+                backslash_count = 0;
+
+                if cat.is_escape(c)
+                {
+                    current_state = StateBks::EscapeBks;
+                    backslash_count += 1;
+                }
+                else
+                {
+                    output.push(c);
+                }
+
+            }
+
+            StateBks::EscapeBks => {
+                if cat.is_escape(c) || cat.is_param(c) || cat.is_comment(c) || cat.is_begin_group(c) || cat.is_end_group(c)
+                {
+                    if cat.is_escape(c)
+                    {
+                        backslash_count += 1;
+
+
+                        for _b in 0..(backslash_count / 2)
+                        {
+                            output.push(escape);
+                        }
+
+                        for _b in 0..(backslash_count % 2)
+                        {
+                            output.push(escape);
+                        }
+                    }
+                    else
+                    {
+                        output.push(c);
+                    }
+
+                    current_state = StateBks::InitialBks;
+                }
+                else if !c.is_ascii_alphanumeric()
+                {
+                    //preserve \s
+                    output.push(escape);
+                    output.push(c);
+
+                    current_state = StateBks::InitialBks;
+                }
+                //if even backslashes then macro is useless
+                else if c.is_ascii_alphanumeric() && backslash_count % 2 == 0
+                {
+                    for _b in 0..(backslash_count / 2)
+                    {
+                        output.push(escape);
+                    }
+
+                    output.push(c);
+
+                    current_state = StateBks::InitialBks;
+
+                    //macro useless, just output as plaintext
+                }
+            }
+        }
+
+        // the bytes of a character copied out come from the bytes of the character read
+        let emitted = output.len() - offsets.len();
+        offsets.extend((0..emitted).map(|k| i + k.min(c.len_utf8() - 1)));
+
+        i += c.len_utf8();
+    }
+    (output, offsets)
+}
+
+
+fn replace_hash_with_arg(macro_value: &str, arg: &str, cat: &Catcodes) -> String {
+    let mut result = String::new();
+    let mut chars = macro_value.chars().peekable();
+    let mut backslash_count = 0;
+
+    let escape = cat.escape_char();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if cat.is_param(c) => {
+                if backslash_count % 2 == 0 {
+                    // if there are an even number of backslashes (including 0), replace '#' with arg
+                    // correctly handle even backslashes by preserving (!!!!) them
+                    for _ in 0..backslash_count {
+                        result.push(escape);
+                    }
+                    result.push_str(arg);
+                } else {
+                    // if there's an odd number of backslashes, it means '#' is escaped
+                    // preserve all backslashes
+                    for _ in 0..backslash_count {
+                        result.push(escape);
+                    }
+                    // add '#' bc the last backslash escapes it
+                    result.push(c);
+                }
+                backslash_count = 0; // reset backslash count
+            },
+            c if cat.is_escape(c) => {
+                backslash_count += 1;
+            },
+            _ => {
+                // every backslash should be preserved!! will go through other machine later
+                for _ in 0..backslash_count {
+                    result.push(escape);
+                }
+                backslash_count = 0; // reset backslash count
+                result.push(c); 
+            }
+        }
+    }
+
+    // handle any remaining backslashes at the end of string
+    for _ in 0..backslash_count {
+        result.push(escape);
+    }
+
+    result
+}
+
+
+
+//DELIMITED PARAMETERS
+
+// parameter text of a tex style definition, broken into literal text and #n slots
+enum ParamPart {
+    Text(String),
+    Arg(usize),
+}
+
+fn parse_params(text: &str, cat: &Catcodes) -> Result<Vec<ParamPart>, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut expected = 1;
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if cat.is_param(c) {
+            match chars.next().and_then(|d| d.to_digit(10)) {
+                Some(n) if n as usize == expected => {
+                    if !literal.is_empty() {
+                        parts.push(ParamPart::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(ParamPart::Arg(expected));
+                    expected += 1;
+                }
+                _ => return Err(format!("parameters must be numbered {}1 to {}9 in order", c, c)),
+            }
+        } else if cat.is_escape(c) {
+            // escaped characters are matched literally
+            literal.push(c);
+            if let Some(next) = chars.next() {
+                literal.push(next);
+            }
+        } else if cat.is_begin_group(c) || cat.is_end_group(c) {
+            return Err("parameter text can't contain braces".to_string());
+        } else {
+            literal.push(c);
+        }
+    }
+
+    if !literal.is_empty() {
+        parts.push(ParamPart::Text(literal));
+    }
+
+    Ok(parts)
+}
+
+// a delimited argument that is exactly one group loses its braces, like in tex
+fn strip_group<'a>(arg: &'a str, cat: &Catcodes) -> &'a str {
+    if begins_group(arg, 0, cat) && match_group(arg, 0, cat) == Ok(arg.len() - 1) {
+        &arg[1..arg.len() - 1]
+    } else {
+        arg
+    }
+}
+
+// matches the parameter text against input starting at start. gives back the
+// arguments and where the use of the macro ends
+fn match_params(input: &str, start: usize, parts: &[ParamPart], cat: &Catcodes) -> Result<(Vec<String>, usize), String> {
+    let bytes = input.as_bytes();
+    let mut args = Vec::new();
+    let mut pos = start;
+
+    for (k, part) in parts.iter().enumerate() {
+        match part {
+            ParamPart::Text(text) => {
+                if !bytes[pos..].starts_with(text.as_bytes()) {
+                    return Err(format!("expected '{}'", text));
+                }
+                pos += text.len();
+            }
+
+            ParamPart::Arg(n) => match parts.get(k + 1) {
+                // delimited, runs up to the delimiter as long as it isn't inside a group
+                Some(ParamPart::Text(delim)) => {
+                    let mut q = pos;
+                    loop {
+                        if q >= input.len() {
+                            return Err(format!("no '{}' found to end argument {}", delim, n));
+                        }
+                        if bytes[q..].starts_with(delim.as_bytes()) {
+                            break;
+                        }
+
+                        let c = bytes[q] as char;
+                        if cat.is_escape(c) {
+                            q += 2;
+                        } else if cat.is_begin_group(c) {
+                            q = match match_group(input, q, cat) {
+                                Ok(end) => end + 1,
+                                Err(_) => return Err(format!("argument {} is not brace balanced", n)),
+                            };
+                        } else if cat.is_end_group(c) {
+                            return Err(format!("group ended before '{}' was found for argument {}", delim, n));
+                        } else {
+                            q += 1;
+                        }
+                    }
+
+                    args.push(strip_group(&input[pos..q], cat).to_string());
+                    pos = q;
+                }
+
+                // undelimited, takes a single group, control word or character. like tex, spaces before it don't count
+                _ => {
+                    while pos < input.len() && bytes[pos].is_ascii_whitespace() {
+                        pos += 1;
+                    }
+
+                    let c = match input[pos..].chars().next() {
+                        Some(c) => c,
+                        None => return Err(format!("missing argument {}", n)),
+                    };
+
+                    if cat.is_begin_group(c) {
+                        let end = match match_group(input, pos, cat) {
+                            Ok(end) => end,
+                            Err(_) => return Err(format!("argument {} is not brace balanced", n)),
+                        };
+                        args.push(input[pos + 1..end].to_string());
+                        pos = end + 1;
+                    } else if cat.is_end_group(c) {
+                        return Err(format!("missing argument {}", n));
+                    } else {
+                        let mut end = pos + c.len_utf8();
+                        if cat.is_escape(c) {
+                            while end < input.len() && bytes[end].is_ascii_alphanumeric() {
+                                end += 1;
+                            }
+                            if end == pos + 1 {
+                                end += input[end..].chars().next().map_or(0, |c| c.len_utf8());
+                            }
+                        }
+                        args.push(input[pos..end].to_string());
+                        pos = end;
+                    }
+                }
+            },
+        }
+    }
+
+    Ok((args, pos))
+}
+
+// fills #1..#9 in with args, ## stands for a single #
+fn replace_params(macro_value: &str, args: &[String], cat: &Catcodes) -> String {
+    let mut result = String::new();
+    let mut chars = macro_value.chars().peekable();
+    let mut backslash_count = 0;
+
+    while let Some(c) = chars.next() {
+        if cat.is_escape(c) {
+            backslash_count += 1;
+            result.push(c);
+            continue;
+        }
+
+        if cat.is_param(c) && backslash_count % 2 == 0 {
+            let slot = chars.peek().and_then(|d| d.to_digit(10)).map(|n| n as usize);
+            match slot {
+                Some(n) if n >= 1 && n <= args.len() => {
+                    chars.next();
+                    result.push_str(&args[n - 1]);
+                }
+                _ => {
+                    if chars.peek().is_some_and(|&d| cat.is_param(d)) {
+                        chars.next();
+                    }
+                    result.push(c);
+                }
+            }
+        } else {
+            result.push(c);
+        }
+
+        backslash_count = 0;
+    }
+
+    result
+}
+
+
+
+#[derive(PartialEq)]
+enum State {
+    Initial,
+    Escape,
+    GenMacro,
+    Def,
+    UserDef,
+    UnDef,
+    If,
+    IfDef,
+    Include,
+    ExpandAfter,
+    Meaning,
+    ShowMacro,
+    Catcode,
+    IncludeOnce
+}
+
+fn process(input: &str, head: &mut Option<Box<MacroNode>>, ctx: &mut Context) -> String {
+
+    let mut current_state = State::Initial;
+    let mut output = String::new();
+    let mut backslash_count = 0;
+
+
+    let _temp = String::new();
+
+    let mut arg1 = String::new();
+    let mut arg2 = String::new();
+    let mut arg3 = String::new();
+
+    let mut argspecial = String::new();
+
+    let mut stop;
+
+    // where the escape of the command being handled sits
+    let mut cmd_start = 0usize;
+
+    let bytes = input.as_bytes(); 
+
+    let mut i = 0usize;
+
+    // die! unless recovering, then the error is recorded and processing goes on
+    // from the end of the broken command (see recovery_point) or from where at says.
+    // either way the error knows where it happened
+    macro_rules! fail {
+        (@report $diagnostic:expr, $kind:expr, $resume:expr) => {{
+            let diagnostic = $diagnostic;
+            if !ctx.recover {
+                Failure { kind: $kind, diagnostic, reported: false }.raise();
+            }
+            ctx.diagnostics.push(diagnostic);
+            i = $resume;
+            current_state = State::Initial;
+            continue;
+        }};
+        // the group read from i doesn't close
+        (unbalanced $unbalanced:expr; $kind:ident, $($arg:tt)*) => {
+            fail!(@report ctx.unbalanced_at(cmd_start, i, $unbalanced, ErrorKind::$kind, format!($($arg)*)), ErrorKind::$kind, recovery_point(input, i, &ctx.catcodes))
+        };
+        // a failure one of the helpers gave back, about the command that ends before end
+        (failure $failure:expr; through $end:expr) => {{
+            let failure: Failure = *$failure;
+            let kind = failure.kind;
+            let mut diagnostic = ctx.diagnostic_at(cmd_start, $end, Severity::Error(kind), kind.code(), failure.diagnostic.message);
+            diagnostic.notes.extend(failure.diagnostic.notes);
+            fail!(@report diagnostic, kind, $end)
+        }};
+        // the group the command needs next doesn't start at i. a closing brace there
+        // that no group is open for is the one to blame, and is skipped
+        (missing $($arg:tt)*) => {{
+            if ends_group(input, i, &ctx.catcodes) && ctx.open_groups == 0 {
+                fail!(@report ctx.unbalanced_at(cmd_start, i, Unbalanced::Extra(i), ErrorKind::Syntax, format!($($arg)*)), ErrorKind::Syntax, i + 1)
+            }
+            fail!(Syntax, $($arg)*)
+        }};
+        (help $help:expr; $kind:ident, $($arg:tt)*) => {{
+            let mut diagnostic = ctx.diagnostic_at(cmd_start, i, Severity::Error(ErrorKind::$kind), ErrorKind::$kind.code(), format!($($arg)*));
+            diagnostic.help = $help;
+            fail!(@report diagnostic, ErrorKind::$kind, recovery_point(input, i, &ctx.catcodes))
+        }};
+        (at $resume:expr; $kind:ident, $($arg:tt)*) => {
+            fail!(@report ctx.diagnostic_at(cmd_start, i, Severity::Error(ErrorKind::$kind), ErrorKind::$kind.code(), format!($($arg)*)), ErrorKind::$kind, $resume)
+        };
+        ($kind:ident, $($arg:tt)*) => {
+            fail!(at recovery_point(input, i, &ctx.catcodes); $kind, $($arg)*)
+        };
+    }
+
+    // a macro used right at the end still has to be looked at, it may not need arguments
+    while i < input.len() || current_state == State::UserDef {
+        let c = char_at(input, i);
+
+        if let Some(max) = ctx.limits.max_output {
+            if ctx.emitted + output.len() > max {
+                ctx.exceeded(i, format!("limit exceeded: output is over {} bytes", max));
+            }
+        }
+
+        if ctx.mappings.is_some() {
+            ctx.map_output(ctx.emitted + output.len(), i);
+        }
+
+        match current_state
+        {
+            State::Initial => {
+                backslash_count = 0;
+
+                if ctx.catcodes.is_escape(c)
+                {
+                    current_state = State::Escape;
+                    backslash_count += 1;
+                }
+                else
+                {
+                    if ctx.catcodes.is_begin_group(c) {
+                        ctx.open_groups += 1;
+                    } else if ctx.catcodes.is_end_group(c) {
+                        ctx.close_group(i);
+                    }
+
+                    output.push(c);
+                    i += c.len_utf8() - 1;
+                }
+
+            }
+
+            State::Escape => {
+                let escape = ctx.catcodes.escape_char();
+
+                if ctx.catcodes.is_escape(c)
+                {
+                    if backslash_count == 1
+                    {
+                        output.push(escape);
+                    }
+
+                    backslash_count += 1;
+
+                    output.push(escape);
+
+                    current_state = State::Escape;
+                }
+                else if ctx.catcodes.is_begin_group(c) || ctx.catcodes.is_end_group(c)
+                {
+                    // after an even run of escapes the brace is a real one
+                    if backslash_count % 2 == 0 {
+                        if ctx.catcodes.is_begin_group(c) {
+                            ctx.open_groups += 1;
+                        } else {
+                            ctx.close_group(i);
+                        }
+                    }
+
+                    if backslash_count == 1
+                    {
+                        output.push(escape);
+                    }
+
+                    output.push(c);
+
+                    current_state = State::Initial;
+                }
+                //if even backslashes then macro is useless
+                else if c.is_ascii_alphanumeric() && backslash_count % 2 == 1
+                {
+
+                    i -= 1;
+
+                    current_state = State::GenMacro;
+
+                    //macro useless, just output as plaintext
+                }
+                else if c.is_ascii_alphanumeric() && backslash_count % 2 == 0
+                {
+                    if backslash_count == 1
+                    {
+                        output.push(escape);
+                    }
+
+                    current_state = State::Initial;
+
+                    output.push(c);
+                    //macro useless, just output as plaintext
+                }
+                else
+                {
+                    if backslash_count == 1
+                    {
+                        output.push(escape);
+                    }
+
+                    output.push(c);
+                    i += c.len_utf8() - 1;
+                    current_state = State::Initial;
+                }
+                //break;
+            }
+
+            State::GenMacro => {
+
+                cmd_start = i - 1;
+
+                ctx.step(cmd_start);
+
+                // read the whole command name, the builtins that take arguments only count when a group follows
+                let start = i;
+
+                while i < input.len() && bytes[i].is_ascii_alphanumeric() {
+                    i += 1;
+                }
+
+                let name = &input[start..i];
+                let group = begins_group(input, i, &ctx.catcodes);
+                let escape_follows = input.as_bytes().get(i).is_some_and(|&b| ctx.catcodes.is_escape(b as char));
+
+                match name {
+                    "ifdef" if group => current_state = State::IfDef,
+                    "def" if group || escape_follows => current_state = State::Def,
+                    "undef" if group => current_state = State::UnDef,
+                    "if" if group => current_state = State::If,
+                    "include" if group => current_state = State::Include,
+                    "includeonce" if group => current_state = State::IncludeOnce,
+                    "expandafter" if group => current_state = State::ExpandAfter,
+                    "meaning" if group => current_state = State::Meaning,
+                    "showmacro" if group => current_state = State::ShowMacro,
+                    "catcode" if group => current_state = State::Catcode,
+                    "listmacros" => {
+                        let mut current = &*head;
+                        while let Some(ref node) = current {
+                            eprintln!("{}", describe_macro(node));
+                            current = &node.next;
+                        }
+
+                        current_state = State::Initial;
+                    }
+                    "tracingon" => {
+                        ctx.tracing = true;
+                        current_state = State::Initial;
+                    }
+                    "tracingoff" => {
+                        ctx.tracing = false;
+                        current_state = State::Initial;
+                    }
+                    _ => {
+                        //user def
+                        argspecial.clear();
+
+                        // extend argspecial with the slice from input
+                        argspecial.push_str(name);
+
+                        if !macro_exists(head, &argspecial) && ctx.lenient {
+                            // written out escaped so process_backslashes leaves a single escape
+                            let escape = ctx.catcodes.escape_char();
+                            output.push(escape);
+                            output.push(escape);
+                            output.push_str(name);
+
+                            if !ctx.passthrough.contains(name) && ctx.warned.insert(name.to_string()) {
+                                let mut diagnostic = ctx.diagnostic_at(cmd_start, i, Severity::Warning, "unknown-command", format!("unknown command \\{} passed through", name));
+                                diagnostic.help = suggest_macro(name, head).map(|name| format!("did you mean {}{}?", escape, name));
+                                ctx.diagnostics.push(diagnostic);
+                            }
+
+                            // its arguments go along untouched, nothing in them is expanded and
+                            // every escape is doubled to come out of process_backslashes as it went in
+                            while begins_group(input, i, &ctx.catcodes) {
+                                let end = match match_group(input, i, &ctx.catcodes) {
+                                    Ok(end) => end,
+                                    Err(_) => break,
+                                };
+                                for c in input[i..=end].chars() {
+                                    if ctx.catcodes.is_escape(c) {
+                                        output.push(c);
+                                    }
+                                    output.push(c);
+                                }
+                                i = end + 1;
+                            }
+
+                            current_state = State::Initial;
+                            continue;
+                        }
+
+                        if !macro_exists(head, &argspecial) {
+                            let escape = ctx.catcodes.escape_char();
+                            let help = suggest_macro(&argspecial, head).map(|name| format!("did you mean {}{}?", escape, name));
+                            fail!(help help; Semantic, "user-def arg not defined: {}", argspecial);
+                        }
+
+                        mark_macro_used(head, &argspecial);
+
+                        current_state = State::UserDef;
+                    }
+                }
+
+                i -= 1;
+
+            }
+
+            State::Def => {
+
+                // tex's own \def\name form always has parameter text, even if it's empty
+                let tex_style = ctx.catcodes.is_escape(c);
+
+                if tex_style {
+                    let start = i + 1;
+                    i = start;
+
+                    while i < input.len() && bytes[i].is_ascii_alphanumeric() {
+                        i += 1;
+                    }
+
+                    arg1.clear();
+                    arg1.push_str(&input[start..i]);
+
+                    if arg1.is_empty() {
+                        fail!(Syntax, "The name cant be empty");
+                    }
+                } else {
+                    if !ctx.catcodes.is_begin_group(c)
+                    {
+                        //ERRCHANGE
+                        fail!(missing "missing name");
+                    }
+
+                    let stop = match match_group(input, i, &ctx.catcodes) {
+                        Ok(end) => end,
+                        Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced"),
+                    };
+
+                    // ensure the first argument is correctly started
+
+                    arg1.clear();
+                    // process the first argument
+                    arg1.push_str(&input[i+1..stop]);
+
+                    //check name
+                    if arg1.is_empty() {
+                        // return an error if name is empty
+                        fail!(Syntax, "The name cant be empty");
+                    }
+
+                    if !arg1.chars().all(|c| c.is_alphanumeric()) {
+                        // return an error
+                        fail!(Syntax, "The name must be alphanumeric");
+                    }
+
+                    // move past the first argument's closing brace
+                    i = stop + 1;
+                }
+
+                // parameter text runs up to the value, escaped characters can't start it
+                let params_start = i;
+
+                while i < input.len() && !begins_group(input, i, &ctx.catcodes) {
+                    if ctx.catcodes.is_escape(bytes[i] as char) {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+
+                i = i.min(input.len());
+
+                let params_text = &input[params_start..i];
+                let has_params = params_text.chars().any(|c| ctx.catcodes.is_param(c));
+
+                // with {name} braces, text without a parameter in it is just junk before the value
+                let params = if tex_style || has_params {
+                    if let Err(message) = parse_params(params_text, &ctx.catcodes) {
+                        fail!(Syntax, "{}", message);
+                    }
+                    Some(params_text.to_string())
+                } else {
+                    i = params_start;
+                    None
+                };
+
+                // Ensure there's a starting brace for the second argument
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing value");
+                }
+
+                let stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced or too many arguments"),
+                };
+
+                // process the second argument
+
+
+                arg2.clear();
+                arg2.extend(input[i+1..stop].chars());
+
+                // move past the second argument's closing brace
+                i = stop + 1;
+
+                // check if macro already exists
+                if find_macro_value(head, &arg1).is_some() {
+                    fail!(at i; Semantic, "Macro '{}' already defined", arg1);
+                } 
+
+                i -= 1;
+
+                // add or update the macro
+                let params_shown = params.as_deref().unwrap_or("");
+                ctx.trace(cmd_start, "def", &format!("{{{}}}{}{{{}}}", arg1, params_shown, arg2), "");
+
+                add_or_update_macro(head, &arg1, &arg2, params, ctx.location_at(cmd_start));
+
+                current_state = State::Initial;
+
+                arg1.clear();
+                arg2.clear();
+
+            }
+
+            State::UnDef => {
+
+                if !ctx.catcodes.is_begin_group(c)
+                {
+                    //ERRCHANGE
+                    fail!(missing "missing name");
+                }
+
+                let stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced"),
+                };
+
+                // ensure the first argument is correctly started
+
+                //NEED TO CLEAR BEFORE EXTEND
+                arg1.clear();
+                // process the first argument
+                arg1.extend(input[i+1..stop].chars());
+
+                // move past the first argument's closing brace
+                i = stop;
+
+
+                // check if macro already exists
+                if find_macro_value(head, &arg1).is_some() {
+                    ctx.trace(cmd_start, "undef", &format!("{{{}}}", arg1), "");
+                    remove_macro(head, &arg1);
+                }
+                else
+                {
+                    //cant undef undefined macro
+                    fail!(at i + 1; Semantic, "can't undef an undefined macro");
+                }
+                
+
+                current_state = State::Initial;
+
+                arg1.clear();
+            }
+
+            State::Meaning => {
+
+                if !ctx.catcodes.is_begin_group(c)
+                {
+                    fail!(missing "missing name");
+                }
+
+                let stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced"),
+                };
+
+
+                arg1.clear();
+                arg1.push_str(&input[i+1..stop]);
+
+                mark_macro_used(head, &arg1);
+
+                // emit the body untouched, doubling backslashes so process_backslashes gives them back
+                match find_macro_value(head, &arg1) {
+                    Some(value) => {
+                        let escape = ctx.catcodes.escape_char();
+                        output.push_str(&value.replace(escape, &escape.to_string().repeat(2)))
+                    }
+                    None => output.push_str("undefined"),
+                }
+
+                i = stop;
+
+                current_state = State::Initial;
+
+                arg1.clear();
+            }
+
+            State::ShowMacro => {
+
+                if !ctx.catcodes.is_begin_group(c)
+                {
+                    fail!(missing "missing name");
+                }
+
+                let stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced"),
+                };
+
+
+                arg1.clear();
+                arg1.push_str(&input[i+1..stop]);
+
+                match find_macro(head, &arg1) {
+                    Some(node) => eprintln!("{}", describe_macro(node)),
+                    None => eprintln!("\\{}=undefined", arg1),
+                }
+
+                i = stop;
+
+                current_state = State::Initial;
+
+                arg1.clear();
+            }
+
+            State::Catcode => {
+
+                if !ctx.catcodes.is_begin_group(c)
+                {
+                    fail!(missing "missing character");
+                }
+
+                let stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced"),
+                };
+
+
+                arg1.clear();
+                arg1.push_str(&input[i+1..stop]);
+
+                i = stop + 1;
+
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing category code");
+                }
+
+                let stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "not brace balanced"),
+                };
+
+
+                arg2.clear();
+                arg2.push_str(&input[i+1..stop]);
+
+                // the character may be escaped, \catcode{\%}{12} is how a comment character gets named
+                let mut chars = arg1.chars();
+                let target = match (chars.next(), chars.next(), chars.next()) {
+                    (Some(c), None, _) => c,
+                    (Some(e), Some(c), None) if ctx.catcodes.is_escape(e) => c,
+                    _ => {
+                        fail!(Syntax, "catcode needs a single character, got '{}'", arg1);
+                    }
+                };
+
+                let code = match arg2.trim().parse::<u32>() {
+                    Ok(code) => code,
+                    Err(_) => {
+                        fail!(Syntax, "bad category code '{}'", arg2);
+                    }
+                };
+
+                ctx.trace(cmd_start, "catcode", &format!("{{{}}}{{{}}}", arg1, arg2), "");
+
+                let was_comment = ctx.catcodes.is_comment(target);
+
+                if let Err(message) = ctx.catcodes.set(target, code) {
+                    fail!(Semantic, "{}", message);
+                }
+
+                // comments are taken out when a file is read, so the rest of this one keeps the old ones
+                if was_comment != ctx.catcodes.is_comment(target) {
+                    let mut diagnostic = ctx.diagnostic_at(
+                        cmd_start,
+                        stop,
+                        Severity::Warning,
+                        "catcode-comment",
+                        format!("changing whether '{}' starts a comment only affects files read after this one", target),
+                    );
+                    diagnostic.help = Some(format!("use --catcode={}={} to change it everywhere", target, code));
+                    ctx.diagnostics.push(diagnostic);
+                }
+
+                i = stop;
+
+                current_state = State::Initial;
+
+                arg1.clear();
+                arg2.clear();
+            }
+
+            State::UserDef => {
+
+                // retrieve the macro value 
+                let (macro_value, params) = match find_macro(head, &argspecial) {
+                    Some(node) => (node.value.clone(), node.params.clone()),
+                    None => {
+                        fail!(Semantic, "Macro '{}' not found", argspecial);
+                    }
+                };
+
+                // where the use of the macro ends
+                let end;
+
+                let mut expanded_macro = match params {
+                    Some(params) => {
+                        let parts = match parse_params(&params, &ctx.catcodes) {
+                            Ok(parts) => parts,
+                            Err(message) => {
+                                fail!(Syntax, "{}", message);
+                            }
+                        };
+
+                        let args = match match_params(input, i, &parts, &ctx.catcodes) {
+                            Ok((args, match_end)) => {
+                                end = match_end;
+                                args
+                            }
+                            Err(message) => {
+                                fail!(Syntax, "use of \\{} doesn't match its definition: {}", argspecial, message);
+                            }
+                        };
+
+                        arg3.clear();
+                        arg3.push_str(&input[i..end]);
+
+                        replace_params(&macro_value, &args, &ctx.catcodes)
+                    }
+                    None => {
+                        if !begins_group(input, i, &ctx.catcodes) {
+                            fail!(Syntax, "Syntax error or missing argument");
+                        }
+
+                        let end_brace = match match_group(input, i, &ctx.catcodes) {
+                            Ok(end) => end,
+                            Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                        };
+
+
+                        //just in case
+                        arg3.clear();
+                        arg3.push_str(&input[i..=end_brace]);
+
+                        end = end_brace + 1;
+
+                        replace_hash_with_arg(&macro_value, &input[i+1..end_brace], &ctx.catcodes)
+                    }
+                };
+
+                //end brace shouldn't have changed
+
+                ctx.trace(cmd_start, &argspecial, &arg3, &expanded_macro);
+
+                let site = ctx.layout.origin_at(cmd_start);
+                let depth = ctx.layout.depth_at(cmd_start) + 1;
+                ctx.layout.consume(end);
+                ctx.layout.prepend(Layout::generated(expanded_macro.len(), site, depth, &argspecial));
+
+                expanded_macro.push_str(&input[end..]);
+
+                //processing rest of it
+
+                let mut processed_arg = String::new();
+                
+                let mut final_arg = process_rest(&expanded_macro, head, ctx, output.len());
+
+                output.push_str(&final_arg);
+
+                arg3.clear();
+                argspecial.clear();
+
+                expanded_macro.clear();
+                processed_arg.clear();
+
+                final_arg.clear();
+                
+                return output;
+            }
+
+            State::If => {
+
+                if !ctx.catcodes.is_begin_group(c) {
+                    fail!(missing "missing condition");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg1.clear();
+                arg1.extend(input[i+1..stop].chars());
+
+                //SECOND ARG
+
+                //this should NOW be pointing to right after the first brace pair
+                i = stop + 1;
+
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing then");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+
+                arg2.clear();
+                arg2.extend(input[i+1..stop].chars());
+                let then_range = (i + 1, stop);
+
+                //now pointing to right after the second brace pair
+                i = stop + 1;
+
+                //THIRD ARG
+
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing else");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg3.clear();
+                arg3.extend(input[i+1..stop].chars());
+                let else_range = (i + 1, stop);
+
+                let index_holder_if = stop;
+
+
+                // determine content based on arg1's condition (non-empty means true)
+                let mut content = if !arg1.is_empty() {
+                    arg2.clone() // clone arg2 if condition is true
+                } else {
+                    arg3.clone() //false
+                };
+
+                ctx.trace(cmd_start, "if", &format!("{{{}}}{{{}}}{{{}}}", arg1, arg2, arg3), &content);
+
+                let (from, to) = if !arg1.is_empty() { then_range } else { else_range };
+                let branch = ctx.layout.slice(from, to);
+                ctx.layout.consume(index_holder_if + 1);
+                ctx.layout.prepend(branch);
+
+                content.extend(input[index_holder_if+1..].chars());
+
+                content = process_rest(&content, head, ctx, output.len());
+
+
+                // add the result to the output
+                output.push_str(&content);
+
+                content.clear();
+
+                return output;
+
+            }
+
+
+            State::IfDef => {
+
+                if !ctx.catcodes.is_begin_group(c) {
+                    fail!(missing "missing condition");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg1.clear();
+                arg1.extend(input[i+1..stop].chars());
+
+                //SECOND ARG
+
+                //this should NOW be pointing to right after the first brace pair
+                i = stop + 1;
+
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing then");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg2.clear();
+                arg2.extend(input[i+1..stop].chars());
+                let then_range = (i + 1, stop);
+
+                //now pointing to right after the second brace pair
+                i = stop + 1;
+
+                //THIRD ARG
+
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing else");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg3.clear();
+                arg3.extend(input[i+1..stop].chars());
+                let else_range = (i + 1, stop);
+
+
+                let index_holder23 = stop; 
+
+
+                // Determine the content based on arg1's condition (non-empty means true)
+                let condition_met = find_macro_value(head, &arg1).is_some();
+
+                // a guard like \ifdef{name} counts as using name
+                mark_macro_used(head, &arg1);
+
+
+                let mut content_ifdef = if condition_met {
+                    arg2.clone() // Clone to get a mutable copy
+                } else {
+                    arg3.clone() // Clone to get a mutable copy
+                };
+
+                ctx.trace(cmd_start, "ifdef", &format!("{{{}}}{{{}}}{{{}}}", arg1, arg2, arg3), &content_ifdef);
+
+                let (from, to) = if condition_met { then_range } else { else_range };
+                let branch = ctx.layout.slice(from, to);
+                ctx.layout.consume(index_holder23 + 1);
+                ctx.layout.prepend(branch);
+
+                content_ifdef.extend(input[index_holder23+1..].chars());
+
+                content_ifdef = process_rest(&content_ifdef, head, ctx, output.len());
+
+                // add the result to the output
+                output.push_str(&content_ifdef);
+
+                content_ifdef.clear();
+
+                return output;
+
+            }
+
+            State::ExpandAfter => {
+
+                if !ctx.catcodes.is_begin_group(c) {
+                    fail!(missing "missing before");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg1.clear();
+                arg1.extend(input[i+1..stop].chars());
+                let before_range = (i + 1, stop);
+
+                //SECOND ARG
+
+                //this should NOW be pointing to right after the first brace pair
+                i = stop + 1;
+
+                if !begins_group(input, i, &ctx.catcodes) {
+                    fail!(missing "missing then");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg2.clear();
+                arg2.extend(input[i+1..stop].chars());
+                let after_range = (i + 1, stop);
+
+                //now pointing to right after the second brace pair
+                i = stop + 1;
+
+                let hold_here = i;
+
+                // process the second arg, it gets its own layout while doing so
+                // none of that output is final, so it isn't mapped either
+                let outer_layout = ctx.layout.clone();
+                ctx.layout = outer_layout.slice(after_range.0, after_range.1);
+                let mappings = ctx.mappings.take();
+                let open_groups = std::mem::take(&mut ctx.open_groups);
+
+                let result_after = process(&arg2, head, ctx);
+
+                ctx.layout = outer_layout;
+                ctx.mappings = mappings;
+                ctx.open_groups = open_groups;
+                ctx.trace(cmd_start, "expandafter", &format!("{{{}}}{{{}}}", arg1, arg2), &format!("{}{}", arg1, result_after));
+
+                let before = ctx.layout.slice(before_range.0, before_range.1);
+                let after = Layout::generated(
+                    result_after.len(),
+                    ctx.layout.origin_at(after_range.0),
+                    ctx.layout.depth_at(after_range.0) + 1,
+                    "expandafter",
+                );
+                ctx.layout.consume(hold_here);
+                ctx.layout.prepend(after);
+                ctx.layout.prepend(before);
+
+                // add the result to the arg1
+                arg1.push_str(&result_after);
+
+                arg1.extend(input[hold_here..].chars());
+
+                // process the second arg and rest of string
+                let result_before = process_rest(&arg1, head, ctx, output.len());
+
+                // add the result to the output
+                output.push_str(&result_before);
+
+                //OUTPUT
+
+                return output;
+
+            }
+
+            State::Include | State::IncludeOnce => {
+
+                if !ctx.catcodes.is_begin_group(c) {
+                    fail!(missing "missing before");
+                }
+
+                stop = match match_group(input, i, &ctx.catcodes) {
+                    Ok(end) => end,
+                    Err(unbalanced) => fail!(unbalanced unbalanced; Syntax, "Not brace balanced"),
+                };
+
+
+                arg1.clear();
+
+                // Check if any character is not allowed
+                let allowed = |c: char| c.is_alphanumeric() || c == '_' || c == '/' || c == '-' || c == '.';
+
+                if !input[i + 1..stop].bytes().all(|b| allowed(b as char)) {
+                    fail!(Syntax, "File name contains invalid characters.");
+                }
+
+                // add the name to arg1
+                arg1.push_str(&input[i + 1..stop]);
+
+                let path = arg1.trim(); // trim any  whitespace
+                //RUST FUNCTION
+
+                // relative paths start from the directory of the file doing the including
+                let base = ctx.dir_at(cmd_start);
+                let includer = ctx.layout.origin_at(cmd_start).map(|(file, _)| file);
+
+                // when recovering, an include that can't be read is left out
+                if let Err(failure) = check_sandbox_path(path, base.as_deref(), ctx) {
+                    fail!(failure failure; through stop + 1);
+                }
+
+                // a file that was read before is skipped entirely by \includeonce
+                if current_state == State::IncludeOnce {
+                    let resolved = match resolve_input(path, base.as_deref(), ctx) {
+                        Ok(resolved) => resolved,
+                        Err(failure) => fail!(failure failure; through stop + 1),
+                    };
+
+                    if ctx.already_included(&resolved) {
+                        ctx.trace(cmd_start, "includeonce", &format!("{{{}}}", path), "<already included>");
+
+                        i = stop + 1;
+                        current_state = State::Initial;
+                        arg1.clear();
+                        continue;
+                    }
+                }
+
+                let file_id = match initial_file_input(path, base.as_deref(), includer, ctx) {
+                    Ok(id) => id,
+                    Err(failure) => fail!(failure failure; through stop + 1),
+                };
+                arg2 = ctx.files[file_id].text.clone();
+
+                //now pointing to right after the first brace pair
+                i = stop + 1;
+
+                let include_hold_here = i;
+
+                ctx.trace(cmd_start, "include", &format!("{{{}}}", path), &format!("<{} bytes from {}>", arg2.len(), path));
+
+                ctx.layout.consume(include_hold_here);
+                ctx.layout.prepend(Layout::file(file_id, arg2.len()));
+
+
+                // process the include
+                //let result_after = process(&arg2, head);
+
+                arg2.extend(input[include_hold_here..].chars());
+
+                // Process the second arg and rest of string
+                let result_winclude = process_rest(&arg2, head, ctx, output.len());
+
+                // add the result to the output
+                output.push_str(&result_winclude);
+
+                //OUTPUT
+
+                return output;
+
+            }
+
+        }
+        //INCREMENT!
+        i += 1;
+
+    }
+
+    return output;
+
+}
+
+
+
+// every place a path could refer to, in the order they're tried. files read
+// from the command line start in the working directory, includes start next to
+// the including file and only try the working directory once nothing else worked
+fn candidate_paths(file_path: &str, base: Option<&Path>, ctx: &Context) -> Vec<PathBuf> {
+    let path = Path::new(file_path);
+
+    if path.is_absolute() {
+        return vec![path.to_path_buf()];
+    }
+
+    let mut candidates = vec![match base {
+        Some(dir) => dir.join(path),
+        None => path.to_path_buf(),
+    }];
+
+    candidates.extend(ctx.search_paths.iter().map(|dir| dir.join(path)));
+
+    if base.is_some() && !candidates.iter().any(|candidate| candidate == path) {
+        candidates.push(path.to_path_buf());
+    }
+
+    candidates
+}
+
+fn resolve_input(file_path: &str, base: Option<&Path>, ctx: &Context) -> Result<PathBuf, Box<Failure>> {
+    let candidates = candidate_paths(file_path, base, ctx);
+
+    match candidates.iter().find(|candidate| ctx.resolver.is_file(candidate)) {
+        Some(resolved) => Ok(resolved.clone()),
+        None => {
+            let searched: Vec<String> = candidates.iter().map(|candidate| candidate.display().to_string()).collect();
+            Err(Failure::new(ErrorKind::Io, format!("file dne: {} (looked for {})", file_path, searched.join(", "))).into())
+        }
+    }
+}
+
+// an include may not name an absolute path or climb out of the sandbox with ..
+fn check_sandbox_path(file_path: &str, base: Option<&Path>, ctx: &Context) -> Result<(), Box<Failure>> {
+    let root = match &ctx.sandbox {
+        Some(root) => root,
+        None => return Ok(()),
+    };
+
+    let path = Path::new(file_path);
+
+    if path.has_root() {
+        return Err(Failure::new(ErrorKind::Sandbox, format!("sandbox violation: absolute include path {}", file_path)).into());
+    }
+
+    let base = canonical_path(base.filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")), ctx);
+
+    if !normalize_path(&base.join(path)).starts_with(root) {
+        return Err(Failure::new(ErrorKind::Sandbox, format!("sandbox violation: {} leaves {}", file_path, root.display())).into());
+    }
+
+    Ok(())
+}
+
+fn canonical_path(path: &Path, ctx: &Context) -> PathBuf {
+    ctx.resolver.canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// process on text that carries on from a call with emitted bytes of output so far
+fn process_rest(input: &str, head: &mut Option<Box<MacroNode>>, ctx: &mut Context, emitted: usize) -> String {
+    ctx.emitted += emitted;
+    let output = process(input, head, ctx);
+    ctx.emitted -= emitted;
+    output
+}
+
+//read in file input and remove comments thru machine, gives back the id of the source.
+//includer is the file doing the \include, if any, and reading a file that is already
+//being included further up is a cycle. nothing is read when it fails, so an include
+//can be skipped
+fn initial_file_input(file_path: &str, base: Option<&Path>, includer: Option<usize>, ctx: &mut Context) -> Result<usize, Box<Failure>> {
+
+    let resolved = resolve_input(file_path, base, ctx)?;
+    let canonical = canonical_path(&resolved, ctx);
+
+    // links can still lead out of the sandbox, so where the file really is gets checked too
+    if let (Some(root), Some(_)) = (&ctx.sandbox, includer) {
+        if !canonical.starts_with(root) {
+            let message = format!("sandbox violation: {} resolves to {}, outside of {}", file_path, canonical.display(), root.display());
+            return Err(Failure::new(ErrorKind::Sandbox, message).into());
+        }
+    }
+
+    if let Some(includer) = includer {
+        let chain = ctx.include_chain(includer);
+
+        if let Some(max) = ctx.limits.max_include_depth {
+            if chain.len() > max {
+                return Err(Failure::new(ErrorKind::Limit, format!("limit exceeded: includes nested more than {} deep", max)).into());
+            }
+        }
+
+        // how it got here is in the notes of the \include that is refused
+        if let Some(&open) = chain.iter().find(|&&file| ctx.files[file].canonical.as_ref() == Some(&canonical)) {
+            let message = format!("include cycle: {} is already being included", ctx.files[open].name);
+            return Err(Failure::new(ErrorKind::Semantic, message).into());
+        }
+    }
+
+    match ctx.resolver.read_to_string(&resolved) {
+        Ok(contents) => {
+            // diagnostics name the file that was actually read
+            let id = ctx.add_source(&resolved.display().to_string(), Some(resolved), contents);
+            ctx.files[id].canonical = Some(canonical);
+            ctx.files[id].included_from = includer;
+            Ok(id)
+        },
+        Err(err) => Err(Failure::new(ErrorKind::Io, format!("can't open {}: {}", resolved.display(), err)).into()),
+    }
+}
+
+
+//JSON
+
+// s as a json string literal, quotes included
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_location(location: &Option<Location>) -> String {
+    match location {
+        Some(location) => format!(
+            "{{\"file\": {}, \"line\": {}, \"col\": {}}}",
+            json_string(&location.file),
+            location.line,
+            location.col
+        ),
+        None => "null".to_string(),
+    }
+}
+
+fn json_position(location: &Location) -> String {
+    format!("{{\"line\": {}, \"col\": {}}}", location.line, location.col)
+}
+
+// --error-format=json, one line per problem:
+//
+//   {"severity": "error", "code": "syntax", "message": "not brace balanced",
+//    "file": "main.tex", "span": {"start": {"line": 3, "col": 1}, "end": {"line": 3, "col": 9}},
+//    "notes": ["chapter.tex was included from main.tex"], "help": null}
+//
+// severity is "error" or "warning". code is the kind of error (usage, io, syntax,
+// semantic, sandbox, limit, internal) or of warning (unused-macro,
+// unknown-command, catcode-comment, unmatched-brace). file and span are null when the problem isn't
+// tied to a place, and span ends where it starts when only the start is known. help is a suggested
+// fix such as "did you mean \\hello?", or null
+fn diagnostic_json(diagnostic: &Diagnostic) -> String {
+    let severity = match diagnostic.severity {
+        Severity::Warning => "warning",
+        Severity::Error(_) => "error",
+    };
+    let (file, span) = match &diagnostic.location {
+        Some(start) => (
+            json_string(&start.file),
+            format!(
+                "{{\"start\": {}, \"end\": {}}}",
+                json_position(start),
+                json_position(diagnostic.end.as_ref().unwrap_or(start))
+            ),
+        ),
+        None => ("null".to_string(), "null".to_string()),
+    };
+    let notes: Vec<String> = diagnostic.notes.iter().map(|note| json_string(note)).collect();
+    let help = diagnostic.help.as_ref().map_or("null".to_string(), |help| json_string(help));
+
+    format!(
+        "{{\"severity\": \"{}\", \"code\": \"{}\", \"message\": {}, \"file\": {}, \"span\": {}, \"notes\": [{}], \"help\": {}}}",
+        severity,
+        diagnostic.code,
+        json_string(&diagnostic.message),
+        file,
+        span,
+        notes.join(", "),
+        help
+    )
+}
+
+// arguments a use of the macro takes, a macro without parameter text takes one group
+fn macro_arity(node: &MacroNode, cat: &Catcodes) -> usize {
+    match &node.params {
+        Some(params) => parse_params(params, cat)
+            .map_or(0, |parts| parts.iter().filter(|part| matches!(part, ParamPart::Arg(_))).count()),
+        None => 1,
+    }
+}
+
+// {"macros": [...]} with the name, parameter text, body, arity and definition of
+// each macro, in the order they were defined
+fn macros_json(head: &Option<Box<MacroNode>>, cat: &Catcodes) -> String {
+    let mut entries = Vec::new();
+    let mut current = head;
+    while let Some(ref node) = current {
+        entries.push(format!(
+            "  {{\"name\": {}, \"params\": {}, \"body\": {}, \"arity\": {}, \"location\": {}}}",
+            json_string(&node.name),
+            node.params.as_deref().map_or("null".to_string(), json_string),
+            json_string(&node.value),
+            macro_arity(node, cat),
+            json_location(&node.location)
+        ));
+        current = &node.next;
+    }
+
+    if entries.is_empty() {
+        return "{\"macros\": []}\n".to_string();
+    }
+    format!("{{\"macros\": [\n{}\n]}}\n", entries.join(",\n"))
+}
+
+
+// the --source-map file:
+//
+//   {"version": 1,
+//    "sources": ["main.tex", "chapter.tex"],
+//    "mappings": [
+//      {"output": {"offset": 0, "line": 1, "col": 1}, "length": 12,
+//       "source": 0, "line": 3, "col": 1, "expansion": null},
+//      ...]}
+//
+// each mapping covers length bytes of output starting at output. source indexes
+// sources, line and col are where the text starts in it (columns count
+// characters, from 1). text a macro produced maps to where the macro was used,
+// and expansion names the macro ("expandafter" for \expandafter), it is null
+// for text copied from a file. source, line and col are null when nothing is known
+fn source_map_json(output: &str, offsets: &[usize], ctx: &Context) -> String {
+    let mappings: &[Mapping] = ctx.mappings.as_deref().unwrap_or(&[]);
+
+    // where each run of output starts: (output offset, line, col, mapping, processed byte)
+    let mut runs: Vec<(usize, usize, usize, Option<usize>, usize)> = Vec::new();
+    let mut previous: Option<(Option<usize>, usize)> = None;
+    let mut line = 1;
+    let mut line_start = 0;
+
+    for (k, &j) in offsets.iter().enumerate() {
+        let index = mappings.partition_point(|mapping| mapping.out <= j).checked_sub(1);
+
+        // file text has to carry on byte for byte, anything a macro made maps to one place
+        let carries_on = previous.is_some_and(|(previous_index, previous_j)| {
+            previous_index == index && index.is_none_or(|m| mappings[m].generated || j == previous_j + 1)
+        });
+
+        if !carries_on && output.is_char_boundary(k) {
+            runs.push((k, line, output[line_start..k].chars().count() + 1, index, j));
+        }
+        previous = Some((index, j));
+
+        if output.as_bytes()[k] == b'\n' {
+            line += 1;
+            line_start = k + 1;
+        }
+    }
+
+    let mut entries = Vec::new();
+    for (n, &(start, line, col, index, j)) in runs.iter().enumerate() {
+        let end = runs.get(n + 1).map_or(output.len(), |next| next.0);
+
+        let origin = index.and_then(|m| {
+            let mapping = &mappings[m];
+            let offset = if mapping.generated { mapping.offset } else { mapping.offset + (j - mapping.out) };
+            mapping.file.map(|file| (file, ctx.files[file].location(offset)))
+        });
+        let (source, origin_line, origin_col) = match &origin {
+            Some((file, location)) => (file.to_string(), location.line.to_string(), location.col.to_string()),
+            None => ("null".to_string(), "null".to_string(), "null".to_string()),
+        };
+        let expansion = index
+            .and_then(|m| mappings[m].expansion.as_deref())
+            .map_or("null".to_string(), json_string);
+
+        entries.push(format!(
+            "    {{\"output\": {{\"offset\": {}, \"line\": {}, \"col\": {}}}, \"length\": {}, \"source\": {}, \"line\": {}, \"col\": {}, \"expansion\": {}}}",
+            start, line, col, end - start, source, origin_line, origin_col, expansion
+        ));
+    }
+
+    let sources: Vec<String> = ctx.files.iter().map(|file| json_string(&file.name)).collect();
+
+    format!(
+        "{{\"version\": 1,\n  \"sources\": [{}],\n  \"mappings\": [\n{}\n  ]}}\n",
+        sources.join(", "),
+        entries.join(",\n")
+    )
+}
+
+// a parsed json value, just enough for reading language server messages
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    // follows a path of object keys, e.g. ["params", "textDocument", "uri"]
+    fn at(&self, path: &[&str]) -> Option<&Json> {
+        path.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "{}", json_string(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (k, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if k == 0 { "" } else { "," }, item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (k, (name, value)) in members.iter().enumerate() {
+                    write!(f, "{}{}:{}", if k == 0 { "" } else { "," }, json_string(name), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.bytes.len() && self.bytes[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.bytes.get(self.pos) != Some(&b) {
+            return Err(format!("expected '{}' at byte {}", b as char, self.pos));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.bytes[self.pos..].starts_with(word.as_bytes()) {
+            return Err(format!("bad literal at byte {}", self.pos));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.bytes.get(self.pos) {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(format!("expected ',' or ']' at byte {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.bytes.get(self.pos) == Some(&b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = self.string()?;
+                    self.expect(b':')?;
+                    members.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.bytes.get(self.pos) {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => return Err(format!("expected ',' or '}}' at byte {}", self.pos)),
+                    }
+                }
+            }
+            Some(b'-') | Some(b'0'..=b'9') => {
+                let start = self.pos;
+                while self.pos < self.bytes.len() && matches!(self.bytes[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') {
+                    self.pos += 1;
+                }
+                let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("");
+                text.parse::<f64>().map(Json::Number).map_err(|_| format!("bad number '{}'", text))
+            }
+            _ => Err(format!("unexpected input at byte {}", self.pos)),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.bytes.get(self.pos..self.pos + 4).and_then(|digits| std::str::from_utf8(digits).ok());
+        let code = digits.and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        self.pos += 4;
+        code.ok_or_else(|| format!("bad \\u escape at byte {}", self.pos - 4))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+
+        loop {
+            match self.bytes.get(self.pos) {
+                None => return Err("unterminated string".to_string()),
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.bytes.get(self.pos) {
+                        Some(&b) => b,
+                        None => return Err("unterminated string".to_string()),
+                    };
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // characters outside the basic plane come as a surrogate pair
+                            if (0xd800..0xdc00).contains(&code) && self.bytes[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        other => return Err(format!("bad escape '\\{}'", other as char)),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                Some(&b) => {
+                    bytes.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        String::from_utf8(bytes).map_err(|_| "string isn't utf-8".to_string())
+    }
+}
+
+fn parse_json(text: &str) -> Result<Json, String> {
+    let mut parser = JsonParser { bytes: text.as_bytes(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.bytes.len() {
+        return Err(format!("trailing input at byte {}", parser.pos));
+    }
+    Ok(value)
+}
+
+
+//SAVED STATE
+
+// the first line of a state file, the number goes up when the format changes
+const STATE_HEADER: &str = "texproc state 1";
+
+// macros and catcodes as a preamble left them
+struct SavedState {
+    catcodes: Catcodes,
+    macros: Option<Box<MacroNode>>,
+}
+
+// after the header come "catcode CHAR CODE" lines for every category that isn't
+// the default, then a "macro" line per macro giving the byte lengths of its name,
+// parameter text, value and defining file ("-" for none) and the line and column,
+// followed by those strings back to back and a newline
+fn save_state(path: &str, head: &Option<Box<MacroNode>>, cat: &Catcodes) {
+    let mut state = format!("{}\n", STATE_HEADER);
+
+    let defaults = Catcodes::default();
+    for c in 0..128usize {
+        if cat.codes[c] != defaults.codes[c] {
+            state.push_str(&format!("catcode {} {}\n", c, cat.codes[c]));
+        }
+    }
+
+    let mut current = head;
+    while let Some(ref node) = current {
+        let params = node.params.as_deref();
+        let (file, line, col) = match &node.location {
+            Some(location) => (Some(location.file.as_str()), location.line, location.col),
+            None => (None, 0, 0),
+        };
+        let length = |text: Option<&str>| text.map_or("-".to_string(), |text| text.len().to_string());
+
+        state.push_str(&format!(
+            "macro {} {} {} {} {} {}\n",
+            node.name.len(),
+            length(params),
+            node.value.len(),
+            length(file),
+            line,
+            col
+        ));
+        state.push_str(&node.name);
+        state.push_str(params.unwrap_or(""));
+        state.push_str(&node.value);
+        state.push_str(file.unwrap_or(""));
+        state.push('\n');
+
+        current = &node.next;
+    }
+
+    if let Err(err) = std::fs::write(path, state) {
+        die!(Io, "can't write {}: {}", path, err);
+    }
+}
+
+fn parse_state(text: &str) -> Result<SavedState, String> {
+    let mut saved = SavedState {
+        catcodes: Catcodes::default(),
+        macros: None,
+    };
+
+    let rest = match text.strip_prefix(STATE_HEADER).and_then(|rest| rest.strip_prefix('\n')) {
+        Some(rest) => rest,
+        None => return Err("not a texproc state file".to_string()),
+    };
+
+    let mut pos = text.len() - rest.len();
+
+    while pos < text.len() {
+        let line_end = match text[pos..].find('\n') {
+            Some(k) => pos + k,
+            None => return Err("truncated".to_string()),
+        };
+        let fields: Vec<&str> = text[pos..line_end].split(' ').collect();
+        pos = line_end + 1;
+
+        let number = |field: &str| field.parse::<usize>().map_err(|_| format!("bad number '{}'", field));
+
+        match fields.as_slice() {
+            ["catcode", c, code] => {
+                let (c, code) = (number(c)?, number(code)?);
+                let code = u8::try_from(code).map_err(|_| format!("bad category code {}", code))?;
+                let known = [CAT_ESCAPE, CAT_BEGIN_GROUP, CAT_END_GROUP, CAT_PARAM, CAT_OTHER, CAT_COMMENT];
+                if c >= 128 || (c as u8).is_ascii_alphanumeric() || !known.contains(&code) {
+                    return Err(format!("bad catcode {} {}", c, code));
                 }
-                else if c == '{' || c == '}'
-                {
-                    if backslash_count == 1
-                    {
-                        output.push('\\');
+                saved.catcodes.codes[c] = code;
+            }
+            ["macro", name, params, value, file, line, col] => {
+                let mut take = |field: &str| -> Result<Option<String>, String> {
+                    if field == "-" {
+                        return Ok(None);
                     }
+                    // the length can be anything, it has to fit in what is left
+                    let end = pos.checked_add(number(field)?).filter(|&end| end <= text.len()).ok_or("truncated")?;
+                    let taken = text.get(pos..end).ok_or("truncated")?;
+                    pos = end;
+                    Ok(Some(taken.to_string()))
+                };
 
-                    output.push(c);
+                let name = take(name)?.unwrap_or_default();
+                let params = take(params)?;
+                let value = take(value)?.unwrap_or_default();
+                let file = take(file)?;
 
-                    current_state = State::Initial;
+                if text[pos..].starts_with('\n') {
+                    pos += 1;
+                } else {
+                    return Err(format!("macro {} runs past its length", name));
                 }
-                //if even backslashes then macro is useless
-                else if c.is_alphanumeric() && backslash_count % 2 == 1
-                {
 
-                    i -= 1;
+                let location = match file {
+                    Some(file) => Some(Location { file, line: number(line)?, col: number(col)?, source: None }),
+                    None => None,
+                };
+                add_or_update_macro(&mut saved.macros, &name, &value, params, location);
+            }
+            _ => return Err(format!("unexpected line '{}'", fields.join(" "))),
+        }
+    }
 
-                    current_state = State::GenMacro;
+    Ok(saved)
+}
 
-                    //macro useless, just output as plaintext
-                }
-                else if c.is_alphanumeric() && backslash_count % 2 == 0
-                {
-                    if backslash_count == 1
-                    {
-                        output.push('\\');
-                    }
+fn load_state(path: &str) -> SavedState {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            die!(Io, "can't read state {}: {}", path, err);
+        }
+    };
 
-                    current_state = State::Initial;
+    match parse_state(&text) {
+        Ok(saved) => saved,
+        Err(message) => {
+            die!(Io, "bad state file {}: {}", path, message);
+        }
+    }
+}
 
-                    output.push(c);
-                    //macro useless, just output as plaintext
-                }
-                else
-                {
-                    if c == '#'
-                    {
-                        if backslash_count == 1
-                        {
-                            output.push('\\');
-                        }
 
-                        output.push('#');
+const VERSION: &str = "0.1.0";
+
+const USAGE: &str = "usage: texproc [options] [file...]
+       texproc lsp [options]
+
+Expands the macros in the given files, or stdin when there are none, and
+prints the result. texproc lsp is a language server for editors, talking over
+stdin and stdout.
+
+options that take a value accept it after = or as the next argument
+(--sandbox=DIR or --sandbox DIR), except --trace whose file is optional.
+
+options:
+  -o FILE                  write the output to FILE instead of stdout
+  -M                       write a make rule for the output instead of the output
+  -MF FILE                 write the make rule to FILE, alongside the output unless -M
+  -MT TARGET               the target of the make rule (default: the -o file, or the
+                           first input with its extension changed to .out)
+  --check                  process everything but only report problems, no output
+  --save-state=FILE        after processing, save the macros and catcodes to FILE
+  --load-state=FILE        start from the macros and catcodes saved in FILE
+  --source-map=FILE        write where each part of the output came from to FILE as json
+  --dump-macros=FILE       after processing, write the macros to FILE as json
+  --repl                   expand what's typed in, files given are read first
+  --watch                  process again whenever one of the files read changes. the
+                           files are checked twice a second, not watched with inotify
+  --error-format=FORMAT    text (the default) or json, one object per line. text is
+                           colored when stderr, where it goes, is a terminal, whatever
+                           stdout is, unless NO_COLOR is set
+  --keep-going             carry on after errors in the text and includes that fail,
+                           report them all at the end
+  --lenient                write unknown commands and the groups right after them out
+                           as they are, with a warning
+  --passthrough=NAME,...   unknown commands that --lenient passes without a warning
+  -D NAME[=VALUE]          define NAME before reading any input (VALUE defaults to 1)
+  -U NAME                  undo an earlier -D NAME
+  -I DIR                   also look for files in DIR, before the ones in TEXPROC_PATH
+  --                       read stdin at this point among the files
+  --sandbox=DIR            includes must stay inside DIR
+  --catcode=CHAR=CODE      give CHAR a tex category code (0, 1, 2, 6, 12 or 14)
+  --trace[=FILE]           log every expansion to stderr or FILE
+  --max-depth=N            expansions nested at most N deep (default 10000)
+  --max-steps=N            run at most N commands
+  --max-output=N           produce at most N bytes
+  --max-include-depth=N    nest includes at most N deep
+  --timeout=SECS           give up after SECS seconds
+  --help                   show this and exit
+  --version                show the version and exit
+
+exit status:
+  0    success
+  2    usage error, bad command line
+  3    i/o error, a file couldn't be found, read or written
+  4    syntax error, e.g. unbalanced braces or a missing argument
+  5    semantic error, e.g. an undefined macro, a redefinition or an include cycle
+  6    an include left the sandbox
+  7    a resource limit was exceeded
+  101  internal error";
+
+// an input named on the command line
+enum Input {
+    File(String),
+    Stdin,
+}
 
-                        current_state = State::Initial;
-                    }
-                    else
-                    {
-                        if backslash_count == 1
-                        {
-                            output.push('\\');
-                        }
+// everything asked for on the command line. it is all checked before any input is read
+struct Options {
+    inputs: Vec<Input>,
+    output: Option<String>,
+    check: bool,
+    error_format: ErrorFormat,
+    lsp: bool,
+    save_state: Option<String>,
+    load_state: Option<String>,
+    dump_macros: Option<String>,
+    source_map: Option<String>,
+    // load_state read in once the options are known to be good, -D, -U and
+    // --catcode apply on top of it
+    state: Option<SavedState>,
+    repl: bool,
+    watch: bool,
+    keep_going: bool,
+    // -M, -MF and -MT
+    deps_only: bool,
+    deps_file: Option<String>,
+    deps_target: Option<String>,
+    lenient: bool,
+    passthrough: HashSet<String>,
+    // -D and -U in order, None undefines
+    defines: Vec<(String, Option<String>)>,
+    search_paths: Vec<PathBuf>,
+    sandbox: Option<PathBuf>,
+    trace: bool,
+    trace_file: Option<String>,
+    catcodes: Catcodes,
+    // the characters --catcode was used on
+    catcodes_given: Vec<char>,
+    limits: Limits,
+}
 
-                        output.push(c);
-                        current_state = State::Initial;
-                    }
-                }
-                //break;
-            }
+// a number given to one of the --max- options
+fn parse_limit(option: &str, value: &str) -> usize {
+    match value.parse::<usize>() {
+        Ok(limit) => limit,
+        Err(_) => {
+            die!(Usage, "{} needs a whole number, got '{}'", option, value);
+        }
+    }
+}
 
-            State::GenMacro => {
+fn check_macro_name(option: &str, name: &str) {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric()) {
+        die!(Usage, "{} needs an alphanumeric macro name, got '{}'", option, name);
+    }
+}
 
-                if i + 5 < input.len() && &input[i..=i+5] == "ifdef{" {
-                    i += 4;
-                    current_state = State::IfDef;
-                } 
-                else if i + 3 < input.len() && &input[i..=i+3] == "def{" {
-                    i += 2;
-                    current_state = State::Def;
-                } else if i + 5 < input.len() && &input[i..=i+5] == "undef{" {
-                    i += 4;
-                    current_state = State::UnDef;
-                } 
-                else if i + 2 < input.len() && &input[i..=i+2] == "if{" {
-                    i += 1;
-                    current_state = State::If;
-                } 
-                else if i + 7 < input.len() && &input[i..=i+7] == "include{" {
-                    i += 6;
-                    current_state = State::Include;
-                } 
-                else if i + 11 < input.len() && &input[i..=i+11] == "expandafter{" {
-                    i += 10;
-                    current_state = State::ExpandAfter;
-                }
-                else
-                {
-                    //user def
-                    let start = i;
+// short options take their value attached (-DX=1) or as the next argument (-D X=1)
+fn value_of(arg: &str, flag: &str, rest: &mut std::slice::Iter<String>) -> String {
+    let attached = &arg[flag.len()..];
+    if !attached.is_empty() {
+        return attached.to_string();
+    }
 
-                    while i < input.len() && input[i..=i].chars().next().map_or(false, |c| c.is_alphanumeric()) {
-                        i += 1;
-                    }
+    match rest.next() {
+        Some(value) => value.clone(),
+        None => {
+            die!(Usage, "{} needs a value (see --help)", flag);
+        }
+    }
+}
 
-                    argspecial.clear();
+// long options take theirs after = (--sandbox=DIR) or as the next argument (--sandbox DIR).
+// None when arg is some other option
+fn long_value(arg: &str, flag: &str, rest: &mut std::slice::Iter<String>) -> Option<String> {
+    if let Some(value) = arg.strip_prefix(flag).and_then(|tail| tail.strip_prefix('=')) {
+        return Some(value.to_string());
+    }
+    if arg != flag {
+        return None;
+    }
 
-                    // extend argspecial with the slice from input
-                    argspecial.extend(input[start..i].chars());
+    match rest.next() {
+        Some(value) => Some(value.clone()),
+        None => {
+            die!(Usage, "{} needs a value (see --help)", flag);
+        }
+    }
+}
 
-                    if !macro_exists(head, &argspecial) {
-                        die!("user-def arg not defined: {}", argspecial);
-                    }
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        inputs: Vec::new(),
+        output: None,
+        check: false,
+        error_format: ErrorFormat::Text,
+        lsp: false,
+        save_state: None,
+        load_state: None,
+        dump_macros: None,
+        source_map: None,
+        state: None,
+        repl: false,
+        watch: false,
+        keep_going: false,
+        deps_only: false,
+        deps_file: None,
+        deps_target: None,
+        lenient: false,
+        passthrough: HashSet::new(),
+        defines: Vec::new(),
+        search_paths: Vec::new(),
+        sandbox: None,
+        trace: false,
+        trace_file: None,
+        catcodes: Catcodes::default(),
+        catcodes_given: Vec::new(),
+        limits: Limits {
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            ..Limits::default()
+        },
+    };
 
-                    i -= 1;
+    let mut rest = args.iter();
+
+    // the subcommand comes before any options
+    if args.first().map(String::as_str) == Some("lsp") {
+        options.lsp = true;
+        rest.next();
+    }
 
-                    current_state = State::UserDef;
+    while let Some(arg) = rest.next() {
+        if arg == "--help" {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        } else if arg == "--version" {
+            println!("texproc {}", VERSION);
+            std::process::exit(0);
+        } else if arg == "--" {
+            options.inputs.push(Input::Stdin);
+        } else if arg == "--check" {
+            options.check = true;
+        } else if let Some(path) = long_value(arg, "--save-state", &mut rest) {
+            options.save_state = Some(path);
+        } else if let Some(path) = long_value(arg, "--dump-macros", &mut rest) {
+            options.dump_macros = Some(path);
+        } else if let Some(path) = long_value(arg, "--source-map", &mut rest) {
+            options.source_map = Some(path);
+        } else if let Some(path) = long_value(arg, "--load-state", &mut rest) {
+            options.load_state = Some(path);
+        } else if arg == "--repl" {
+            options.repl = true;
+        } else if arg == "--watch" {
+            options.watch = true;
+        } else if let Some(format) = long_value(arg, "--error-format", &mut rest) {
+            options.error_format = match format.as_str() {
+                "text" => ErrorFormat::Text,
+                "json" => ErrorFormat::Json,
+                _ => {
+                    die!(Usage, "--error-format is text or json, got '{}'", format);
+                }
+            };
+        } else if arg == "--keep-going" {
+            options.keep_going = true;
+        } else if arg == "--lenient" {
+            options.lenient = true;
+        } else if let Some(names) = long_value(arg, "--passthrough", &mut rest) {
+            // an allow-list only makes sense when unknown commands pass
+            options.lenient = true;
+            for name in names.split(',').filter(|name| !name.is_empty()) {
+                check_macro_name("--passthrough", name);
+                options.passthrough.insert(name.to_string());
+            }
+        } else if arg.starts_with("-MF") {
+            options.deps_file = Some(value_of(arg, "-MF", &mut rest));
+        } else if arg.starts_with("-MT") {
+            options.deps_target = Some(value_of(arg, "-MT", &mut rest));
+        } else if arg == "-M" {
+            options.deps_only = true;
+        } else if arg.starts_with("-o") {
+            options.output = Some(value_of(arg, "-o", &mut rest));
+        } else if arg.starts_with("-D") {
+            let define = value_of(arg, "-D", &mut rest);
+            let (name, value) = match define.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (define.clone(), "1".to_string()),
+            };
+            check_macro_name("-D", &name);
+            options.defines.push((name, Some(value)));
+        } else if arg.starts_with("-U") {
+            let name = value_of(arg, "-U", &mut rest);
+            check_macro_name("-U", &name);
+            options.defines.push((name, None));
+        } else if arg.starts_with("-I") {
+            options.search_paths.push(PathBuf::from(value_of(arg, "-I", &mut rest)));
+        } else if let Some(root) = long_value(arg, "--sandbox", &mut rest) {
+            match std::fs::canonicalize(&root) {
+                Ok(canonical) if canonical.is_dir() => options.sandbox = Some(canonical),
+                _ => {
+                    die!(Usage, "sandbox root {} is not a directory", root);
+                }
+            }
+        } else if let Some(value) = long_value(arg, "--max-depth", &mut rest) {
+            options.limits.max_depth = Some(parse_limit("--max-depth", &value));
+        } else if let Some(value) = long_value(arg, "--max-steps", &mut rest) {
+            options.limits.max_steps = Some(parse_limit("--max-steps", &value));
+        } else if let Some(value) = long_value(arg, "--max-output", &mut rest) {
+            options.limits.max_output = Some(parse_limit("--max-output", &value));
+        } else if let Some(value) = long_value(arg, "--max-include-depth", &mut rest) {
+            options.limits.max_include_depth = Some(parse_limit("--max-include-depth", &value));
+        } else if let Some(value) = long_value(arg, "--timeout", &mut rest) {
+            // in seconds, fractions allowed
+            match value.parse::<f64>() {
+                Ok(secs) if secs.is_finite() && secs > 0.0 => options.limits.timeout = Some(Duration::from_secs_f64(secs)),
+                _ => {
+                    die!(Usage, "--timeout needs a number of seconds, got '{}'", value);
                 }
+            }
+        } else if arg == "--trace" {
+            options.trace = true;
+        } else if let Some(trace_path) = arg.strip_prefix("--trace=") {
+            options.trace = true;
+            options.trace_file = Some(trace_path.to_string());
+        } else if let Some(setting) = long_value(arg, "--catcode", &mut rest) {
+            // --catcode=%=12, the character comes first so it can be '=' too
+            let mut chars = setting.chars();
+            let target = chars.next();
+            let code = chars.as_str().strip_prefix('=').and_then(|code| code.parse::<u32>().ok());
+
+            let (target, code) = match (target, code) {
+                (Some(target), Some(code)) => (target, code),
+                _ => {
+                    die!(Usage, "bad --catcode setting '{}', expected CHAR=CODE", setting);
+                }
+            };
 
+            if let Err(message) = options.catcodes.set(target, code) {
+                die!(Usage, "{}", message);
             }
+            options.catcodes_given.push(target);
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            die!(Usage, "unknown option {} (see --help)", arg);
+        } else {
+            options.inputs.push(Input::File(arg.clone()));
+        }
+    }
 
-            State::Def => {
+    if options.lsp && !options.inputs.is_empty() {
+        die!(Usage, "texproc lsp takes no files, the editor sends them");
+    }
 
-                if c != '{'
-                {
-                    //ERRCHANGE
-                    die!("missing name");
-                }
+    if options.repl {
+        if options.inputs.iter().any(|input| matches!(input, Input::Stdin)) {
+            die!(Usage, "--repl reads stdin itself, -- can't go with it");
+        }
+        if options.watch || options.check || options.output.is_some() || options.deps_only || options.deps_file.is_some() {
+            die!(Usage, "--repl can't go with --watch, --check, -o or -M");
+        }
+    }
 
-                if !is_brace_balanced(input, i) {
-                    die!("not brace balanced");
-                }
+    // no files specified; read from stdin
+    if options.inputs.is_empty() && !options.repl && !options.lsp {
+        options.inputs.push(Input::Stdin);
+    }
 
-                // ensure the first argument is correctly started
-                let stop = brace_balance_end(input, i);
+    if options.watch && options.inputs.iter().any(|input| matches!(input, Input::Stdin)) {
+        die!(Usage, "--watch needs files, stdin can't be read again");
+    }
 
-                arg1.clear();
-                // process the first argument
-                arg1.extend(input[i+1..stop].chars());
+    if options.check && options.output.is_some() {
+        die!(Usage, "--check doesn't produce output, -o can't go with it");
+    }
 
-                //check name
-                if arg1.is_empty() {
-                    // return an error if name is empty
-                    die!("The name cant be empty");
-                }
-            
-                if !arg1.chars().all(|c| c.is_alphanumeric()) {
-                    // return an error
-                    die!("The name must be alphanumeric");
-                }
+    let wants_deps = options.deps_only || options.deps_file.is_some();
+    if wants_deps && options.deps_target.is_none() && options.output.is_none() {
+        match options.inputs.first() {
+            Some(Input::File(first)) => {
+                options.deps_target = Some(Path::new(first).with_extension("out").display().to_string());
+            }
+            _ => {
+                die!(Usage, "the make rule needs a target, give -o or -MT");
+            }
+        }
+    }
 
-                // move past the first argument's closing brace
-                i = stop + 1;
+    options.state = options.load_state.as_deref().map(load_state);
 
-                // Ensure there's a starting brace for the second argument
-                if i >= input.len() || input.as_bytes()[i] != b'{' {
-                    die!("missing value");
-                }
+    options
+}
 
-                if !is_brace_balanced(input, i) {
-                    die!("not brace balanced or too many arguments");
-                }
+// a fresh context for processing the inputs once
+fn new_context(options: &Options) -> Context {
+    let mut ctx = Context {
+        search_paths: options.search_paths.clone(),
+        sandbox: options.sandbox.clone(),
+        tracing: options.trace,
+        catcodes: options.catcodes.clone(),
+        limits: options.limits.clone(),
+        // a check should find every problem, not just the first
+        recover: options.keep_going || options.check,
+        lenient: options.lenient,
+        passthrough: options.passthrough.clone(),
+        mappings: options.source_map.as_ref().map(|_| Vec::new()),
+        error_format: options.error_format,
+        ..Context::default()
+    };
 
-                // process the second argument
+    // a saved state's catcodes, with the ones given on the command line over them
+    if let Some(state) = &options.state {
+        ctx.catcodes = state.catcodes.clone();
+        for &c in &options.catcodes_given {
+            ctx.catcodes.codes[c as usize] = options.catcodes.codes[c as usize];
+        }
+    }
+
+    if let Some(trace_path) = &options.trace_file {
+        let trace_file = match File::create(trace_path) {
+            Ok(file) => file,
+            Err(err) => {
+                die!(Io, "can't open trace file {}: {}", trace_path, err);
+            }
+        };
+        ctx.trace_file = Some(Box::new(LineWriter::new(trace_file)));
+    }
 
-                let stop = brace_balance_end(input, i);
+    // the environment comes after anything given with -I
+    if let Some(texproc_path) = std::env::var_os("TEXPROC_PATH") {
+        ctx.search_paths.extend(std::env::split_paths(&texproc_path).filter(|dir| !dir.as_os_str().is_empty()));
+    }
 
-                arg2.clear();
-                arg2.extend(input[i+1..stop].chars());
+    ctx
+}
 
-                // move past the second argument's closing brace
-                i = stop + 1;
+// the macros there are before any input, from a saved state and then -D and -U in the order they were given
+fn initial_macros(options: &Options) -> Option<Box<MacroNode>> {
+    let mut head = options.state.as_ref().and_then(|state| state.macros.clone());
 
-                // check if macro already exists
-                if find_macro_value(head, &arg1).is_some() {
-                    die!("Macro '{}' already defined", arg1);
-                } 
+    for (name, value) in &options.defines {
+        match value {
+            Some(value) => add_or_update_macro(&mut head, name, value, None, None),
+            None => remove_macro(&mut head, name),
+        }
+    }
 
-                i -= 1;
+    head
+}
 
-                // add or update the macro
-                add_or_update_macro(head, &arg1, &arg2);
+// reads the inputs, processes them and writes out whatever the options ask for.
+// an error that stops everything still lets out the problems found before it
+fn process_inputs(options: &Options, ctx: &mut Context) -> io::Result<()> {
+    match panic::catch_unwind(panic::AssertUnwindSafe(|| expand_inputs(options, ctx))) {
+        Ok(result) => result?,
+        Err(payload) => {
+            print_diagnostics(&ctx.diagnostics, ctx.error_format);
+            panic::resume_unwind(payload);
+        }
+    }
 
-                current_state = State::Initial;
+    report_diagnostics(&ctx.diagnostics, ctx.error_format);
 
-                arg1.clear();
-                arg2.clear();
+    Ok(())
+}
 
+fn expand_inputs(options: &Options, ctx: &mut Context) -> io::Result<()> {
+    let mut combined_contents = String::new();
+    let mut sources = Vec::new();
+
+    for input in &options.inputs {
+        match input {
+            // read from the files listed in the cml args
+            Input::File(file_path) => sources.push(initial_file_input(file_path, None, None, ctx).unwrap_or_else(|failure| failure.raise())),
+            Input::Stdin => {
+                let mut contents = String::new();
+                stdin().read_to_string(&mut contents)?;
+                // process the stdin contents and  remove comments
+                //not using initial FILE input bc no file
+                sources.push(ctx.add_source("<stdin>", None, contents));
             }
+        }
+    }
 
-            State::UnDef => {
+    // the layout starts out as the inputs back to back
+    for &id in sources.iter().rev() {
+        let text = &ctx.files[id].text;
+        ctx.layout.prepend(Layout::file(id, text.len()));
+    }
+    for &id in &sources {
+        combined_contents.push_str(&ctx.files[id].text);
+    }
 
-                if c != '{'
-                {
-                    //ERRCHANGE
-                    die!("missing name");
-                }
+    let mut my_head = initial_macros(options);
 
-                if !is_brace_balanced(input, i) {
-                    die!("not brace balanced");
-                }
+    ctx.started = Some(Instant::now());
 
-                // ensure the first argument is correctly started
-                let stop = brace_balance_end(input, i);
+    let processed = process(&combined_contents, &mut my_head, ctx);
 
-                //NEED TO CLEAR BEFORE EXTEND
-                arg1.clear();
-                // process the first argument
-                arg1.extend(input[i+1..stop].chars());
+    let (processed_final, final_offsets) = process_backslashes(&processed, &mut my_head, &ctx.catcodes);
 
-                // move past the first argument's closing brace
-                i = stop;
+    if let Some(state_path) = &options.save_state {
+        save_state(state_path, &my_head, &ctx.catcodes);
+    }
 
+    if let Some(map_path) = &options.source_map {
+        if let Err(err) = std::fs::write(map_path, source_map_json(&processed_final, &final_offsets, ctx)) {
+            die!(Io, "can't write {}: {}", map_path, err);
+        }
+    }
 
-                // check if macro already exists
-                if find_macro_value(head, &arg1).is_some() {
-                    remove_macro(head, &arg1);
+    if let Some(dump_path) = &options.dump_macros {
+        if let Err(err) = std::fs::write(dump_path, macros_json(&my_head, &ctx.catcodes)) {
+            die!(Io, "can't write {}: {}", dump_path, err);
+        }
+    }
+
+    if options.deps_only || options.deps_file.is_some() {
+        let target = options.deps_target.as_ref().or(options.output.as_ref()).unwrap();
+        let rule = dependency_rule(target, ctx);
+
+        match &options.deps_file {
+            Some(deps_path) => {
+                if let Err(err) = std::fs::write(deps_path, rule) {
+                    die!(Io, "can't write {}: {}", deps_path, err);
                 }
-                else
-                {
-                    //cant undef undefined macro
-                    die!("can't undef an undefined macro");
+            }
+            None => print!("{}", rule),
+        }
+    }
+
+    if options.check {
+        let unused = unused_macros(&my_head, ctx);
+        ctx.diagnostics.extend(unused);
+    } else if !options.deps_only {
+        // process the concatenated contents
+        match &options.output {
+            Some(output_path) => {
+                if let Err(err) = std::fs::write(output_path, processed_final) {
+                    die!(Io, "can't write {}: {}", output_path, err);
                 }
-                
+            }
+            None => print!("{}", processed_final),
+        }
+    }
 
-                current_state = State::Initial;
+    Ok(())
+}
 
-                arg1.clear();
+// when each watched file was last changed, None if it can't be looked at (e.g. it was deleted)
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths.iter().map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok()).collect()
+}
+
+// how often --watch looks at the files again. being told about changes (inotify on
+// linux) takes unsafe code or a crate, and this file has neither, so it polls
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+// processes the inputs over and over, each time one of the files read last
+// time changes. failures are reported but don't end the watch
+fn watch(options: &Options) -> ! {
+    loop {
+        // a file saved while the run is going on has to bring on the next one
+        let started = SystemTime::now();
+        let mut ctx = new_context(options);
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| process_inputs(options, &mut ctx)));
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => eprintln!("proj3: {}", err),
+            Err(payload) => {
+                report_failure(&*payload, options.error_format);
             }
+        }
 
-            State::UserDef => {
+        // the output has to show up now, not whenever the next run happens to end a line
+        let _ = io::stdout().flush();
+
+        // the inputs themselves are watched even when they couldn't be read, so creating one counts
+        let mut paths: Vec<PathBuf> = options.inputs.iter().filter_map(|input| match input {
+            Input::File(file_path) => Some(PathBuf::from(file_path)),
+            Input::Stdin => None,
+        }).collect();
+        for file in &ctx.files {
+            if let Some(path) = &file.path {
+                if !paths.contains(path) {
+                    paths.push(path.clone());
+                }
+            }
+        }
 
-                // retrieve the macro value 
-                let macro_value = match find_macro_value(head, &argspecial) {
-                    Some(value) => value,
-                    None => {
-                        die!("Macro '{}' not found", argspecial);
-                    }
-                };
+        eprintln!("proj3: watching {} for changes", plural(paths.len(), "file"));
 
-                if input.as_bytes().get(i) != Some(&b'{') {
-                    die!("Syntax error or missing argument");
-                }
+        let before = modification_times(&paths);
+        let changed_during_run = before.iter().flatten().any(|&modified| modified >= started);
+        while !changed_during_run && modification_times(&paths) == before {
+            std::thread::sleep(WATCH_INTERVAL);
+        }
+    }
+}
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+const REPL_HELP: &str = "type text to expand it, a group that isn't closed continues on the next line
+  :macros       list the macros defined so far
+  :load FILE    process FILE as if it were typed in
+  :reset        forget everything defined since starting
+  :help         show this
+  :quit         leave (so does end of input)";
+
+// whether text can be expanded yet, every group in it has to be closed
+fn repl_entry_complete(text: &str, cat: &Catcodes) -> bool {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+
+    while i < text.len() {
+        if cat.is_escape(bytes[i] as char) {
+            i += 2;
+        } else if begins_group(text, i, cat) {
+            match match_group(text, i, cat) {
+                Ok(end) => i = end + 1,
+                Err(_) => return false,
+            }
+        } else {
+            i += 1;
+        }
+    }
 
-                let end_brace = brace_balance_end(input, i);
+    true
+}
 
-                //just in case
-                arg3.clear();
-                arg3.extend(input[i+1..end_brace].chars());
+// expands source file id on its own, as one entry of the repl. errors are
+// reported and the macros defined before them are kept
+fn repl_expand(id: usize, head: &mut Option<Box<MacroNode>>, ctx: &mut Context) {
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        // the limits are for each entry
+        let text = ctx.files[id].text.clone();
+        ctx.layout = Layout::file(id, text.len());
+        ctx.steps = 0;
+        ctx.emitted = 0;
+        ctx.open_groups = 0;
+        ctx.started = Some(Instant::now());
+
+        let processed = process(&text, head, ctx);
+        let (expanded, _) = process_backslashes(&processed, head, &ctx.catcodes);
+
+        print!("{}", expanded);
+        if !expanded.is_empty() && !expanded.ends_with('\n') {
+            println!();
+        }
 
-                let mut expanded_macro = replace_hash_with_arg(&macro_value, &arg3);
+        let diagnostics = std::mem::take(&mut ctx.diagnostics);
+        report_diagnostics(&diagnostics, ctx.error_format);
+    }));
 
-                //end brace shouldn't have changed
+    // whatever is left was found before something stopped the entry
+    print_diagnostics(&ctx.diagnostics, ctx.error_format);
+    ctx.diagnostics.clear();
 
-                expanded_macro.extend(input[end_brace+1..input.len()].chars());
+    if let Err(payload) = result {
+        report_failure(&*payload, ctx.error_format);
+    }
+}
 
-                //processing rest of it
+fn repl_load(file_path: &str, head: &mut Option<Box<MacroNode>>, ctx: &mut Context) {
+    match initial_file_input(file_path, None, None, ctx) {
+        Ok(id) => repl_expand(id, head, ctx),
+        Err(failure) => {
+            report_failure(&*failure, ctx.error_format);
+        }
+    }
+}
 
-                let mut processed_arg = String::new();
-                
-                let mut final_arg = process(&expanded_macro, head, initial_string);
+// one macro table for a whole session of typed in text
+fn repl(options: &Options) -> io::Result<()> {
+    let mut ctx = new_context(options);
+    let mut head = initial_macros(options);
 
-                output.push_str(&final_arg);
+    for input in &options.inputs {
+        if let Input::File(file_path) = input {
+            repl_load(file_path, &mut head, &mut ctx);
+        }
+    }
 
-                arg3.clear();
-                argspecial.clear();
+    // prompts only make sense to someone typing
+    let interactive = stdin().is_terminal();
+    let mut lines = stdin().lock();
+    let mut entry = String::new();
 
-                expanded_macro.clear();
-                processed_arg.clear();
+    loop {
+        if interactive {
+            print!("{}", if entry.is_empty() { "> " } else { "... " });
+            io::stdout().flush()?;
+        }
 
-                final_arg.clear();
-                
-                return output;
+        let mut line = String::new();
+        if lines.read_line(&mut line)? == 0 {
+            break;
+        }
+
+        if entry.is_empty() && line.starts_with(':') {
+            let (command, argument) = match line.trim().split_once(char::is_whitespace) {
+                Some((command, argument)) => (command.to_string(), argument.trim().to_string()),
+                None => (line.trim().to_string(), String::new()),
+            };
+
+            match command.as_str() {
+                ":quit" | ":q" => break,
+                ":help" => println!("{}", REPL_HELP),
+                ":macros" => {
+                    let mut current = &head;
+                    while let Some(ref node) = current {
+                        println!("{}", describe_macro(node));
+                        current = &node.next;
+                    }
+                }
+                ":reset" => {
+                    ctx = new_context(options);
+                    head = initial_macros(options);
+                }
+                ":load" if !argument.is_empty() => repl_load(&argument, &mut head, &mut ctx),
+                ":load" => eprintln!("proj3: :load needs a file"),
+                _ => eprintln!("proj3: unknown command {} (see :help)", command),
             }
+            continue;
+        }
 
-            State::If => {
+        entry.push_str(&line);
 
-                if c != '{' {
-                    die!("missing condition");
-                }
+        // a brace in a comment doesn't keep the entry open
+        if !repl_entry_complete(&process_comments(&entry, &ctx.catcodes).0, &ctx.catcodes) {
+            continue;
+        }
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+        let id = ctx.add_source("<repl>", None, std::mem::take(&mut entry));
+        repl_expand(id, &mut head, &mut ctx);
+    }
+
+    Ok(())
+}
+
+//LANGUAGE SERVER
+
+// file:// uris the editor uses for documents, as paths and back
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?;
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::new();
+    let mut k = 0;
+
+    while k < bytes.len() {
+        let escaped = bytes.get(k + 1..k + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[k], escaped) {
+            (b'%', Some(b)) => {
+                decoded.push(b);
+                k += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                k += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok().map(PathBuf::from)
+}
 
-                stop = brace_balance_end(input, i);
+fn path_to_uri(path: &Path) -> String {
+    let absolute = match std::env::current_dir() {
+        Ok(cwd) if path.is_relative() => normalize_path(&cwd.join(path)),
+        _ => path.to_path_buf(),
+    };
 
-                arg1.clear();
-                arg1.extend(input[i+1..stop].chars());
+    let mut uri = String::from("file://");
+    for &b in absolute.display().to_string().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
 
-                //SECOND ARG
+// the lsp position of a 1 based line and column of text. columns count characters
+// but the protocol counts utf-16 units, places past the end of the line one each
+fn lsp_position(text: &str, (line, col): (usize, usize)) -> String {
+    let line_text = text.split('\n').nth(line.saturating_sub(1)).unwrap_or("");
+    let character: usize = line_text.chars().chain(std::iter::repeat(' ')).take(col.saturating_sub(1)).map(char::len_utf16).sum();
+    format!("{{\"line\":{},\"character\":{}}}", line.saturating_sub(1), character)
+}
 
-                //this should NOW be pointing to right after the first brace pair
-                i = stop + 1;
+// the range of text from start up to end, or one character long when there's no end after it
+fn lsp_range(text: &str, start: (usize, usize), end: Option<(usize, usize)>) -> String {
+    let end = end.filter(|&end| end > start).unwrap_or((start.0, start.1 + 1));
+    format!("{{\"start\":{},\"end\":{}}}", lsp_position(text, start), lsp_position(text, end))
+}
 
-                if input.as_bytes().get(i) != Some(&b'{') {
-                    die!("missing then");
-                }
+// byte offset in text of an lsp position, counting characters the way the protocol does (utf-16)
+fn lsp_offset(text: &str, line: usize, character: usize) -> usize {
+    let line_start = text.split_inclusive('\n').take(line).map(str::len).sum::<usize>();
+    let mut units = 0;
+    for (k, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + k;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+// what was learned about a document by processing it
+struct Analysis {
+    diagnostics: Vec<Diagnostic>,
+    macros: Option<Box<MacroNode>>,
+    // the name the document's diagnostics and definitions carry
+    name: String,
+    // what was analyzed, and the catcodes it left in place
+    text: String,
+    catcodes: Catcodes,
+}
 
-                stop = brace_balance_end(input, i);
+// processes the document at path the way the command line would, but collecting
+// every problem instead of stopping and without writing anything
+fn lsp_analyze(options: &Options, documents: &MemoryResolver, path: &Path) -> Analysis {
+    let mut ctx = new_context(options);
+    ctx.resolver = Box::new(EditorResolver { documents: documents.clone() });
+    ctx.recover = true;
+    ctx.tracing = false;
+    ctx.trace_file = None;
+
+    let mut head = initial_macros(options);
+    let name = path.display().to_string();
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        let id = initial_file_input(&name, None, None, &mut ctx).unwrap_or_else(|failure| failure.raise());
+        let text = ctx.files[id].text.clone();
+        ctx.layout = Layout::file(id, text.len());
+        ctx.started = Some(Instant::now());
+        process(&text, &mut head, &mut ctx);
+        io::Result::Ok(())
+    }));
+
+    // something that stops processing without a place in the text goes at the top
+    let failure = match result {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(Failure::new(ErrorKind::Io, err.to_string()).diagnostic),
+        Err(payload) => match payload.downcast::<Failure>() {
+            Ok(failure) => Some(failure.diagnostic),
+            Err(payload) => Some(Failure::new(ErrorKind::Syntax, failure_status(&*payload).1).diagnostic),
+        },
+    };
+    if let Some(mut diagnostic) = failure {
+        if diagnostic.location.is_none() {
+            diagnostic.location = Some(Location { file: name.clone(), line: 1, col: 1, source: None });
+        }
+        ctx.diagnostics.push(diagnostic);
+    }
 
+    let unused = unused_macros(&head, &ctx);
+    ctx.diagnostics.extend(unused);
 
-                arg2.clear();
-                arg2.extend(input[i+1..stop].chars());
+    Analysis {
+        diagnostics: ctx.diagnostics,
+        macros: head,
+        name,
+        text: documents.read_to_string(path).unwrap_or_default(),
+        catcodes: ctx.catcodes,
+    }
+}
 
-                //now pointing to right after the second brace pair
-                i = stop + 1;
+fn lsp_diagnostics_json(analysis: &Analysis) -> String {
+    let mut items = Vec::new();
+
+    for diagnostic in &analysis.diagnostics {
+        // problems in included files are reported with those files
+        let location = match &diagnostic.location {
+            Some(location) if location.file == analysis.name => location,
+            _ => continue,
+        };
+        let severity = match diagnostic.severity {
+            Severity::Error(_) => 1,
+            Severity::Warning => 2,
+        };
+        let message = match &diagnostic.help {
+            Some(help) => format!("{}\n{}", diagnostic.message, help),
+            None => diagnostic.message.clone(),
+        };
+        items.push(format!(
+            "{{\"range\":{},\"severity\":{},\"source\":\"texproc\",\"message\":{}}}",
+            lsp_range(
+                &analysis.text,
+                (location.line, location.col),
+                diagnostic.end.as_ref().map(|end| (end.line, end.col))
+            ),
+            severity,
+            json_string(&message)
+        ));
+    }
 
-                //THIRD ARG
+    format!("[{}]", items.join(","))
+}
 
-                if input.as_bytes().get(i) != Some(&b'{') {
-                    die!("missing else");
-                }
+// what the cursor at offset is on: a command name or the file name of an include
+enum LspTarget {
+    Command(String),
+    Include(String),
+}
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+fn lsp_target(text: &str, offset: usize, cat: &Catcodes) -> Option<LspTarget> {
+    let bytes = text.as_bytes();
+    let offset = offset.min(text.len());
 
-                stop = brace_balance_end(input, i);
+    let mut start = offset;
+    while start > 0 && bytes[start - 1].is_ascii_alphanumeric() {
+        start -= 1;
+    }
+    let mut end = offset;
+    while end < text.len() && bytes[end].is_ascii_alphanumeric() {
+        end += 1;
+    }
 
-                arg3.clear();
-                arg3.extend(input[i+1..stop].chars());
+    if start > 0 && cat.is_escape(bytes[start - 1] as char) && start < end {
+        return Some(LspTarget::Command(text[start..end].to_string()));
+    }
 
-                let index_holder_if = stop;
+    // inside the braces of \include{...} or \includeonce{...} on one line
+    let open = text[..offset].rfind(|c: char| cat.is_begin_group(c) || c == '\n')?;
+    let close = open + text[open..].find(|c: char| cat.is_end_group(c) || c == '\n')?;
+    if !cat.is_begin_group(bytes[open] as char) || !cat.is_end_group(bytes[close] as char) || close < offset {
+        return None;
+    }
 
+    let before = &text[..open];
+    let name_start = before.rfind(|c: char| !c.is_ascii_alphanumeric()).map_or(0, |k| k + 1);
+    let command = &before[name_start..];
+    let escaped = name_start > 0 && cat.is_escape(bytes[name_start - 1] as char);
 
-                // determine content based on arg1's condition (non-empty means true)
-                let mut content = if !arg1.is_empty() {
-                    arg2.clone() // clone arg2 if condition is true
-                } else {
-                    arg3.clone() //false
-                };
+    if escaped && (command == "include" || command == "includeonce") {
+        return Some(LspTarget::Include(text[open + 1..close].trim().to_string()));
+    }
+    None
+}
 
+// the response to one request, None for notifications and anything that gets no answer
+fn lsp_handle(
+    message: &Json,
+    options: &Options,
+    documents: &mut MemoryResolver,
+    analyses: &mut HashMap<String, Analysis>,
+    notifications: &mut Vec<String>,
+) -> Option<String> {
+    let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+    let uri = message.at(&["params", "textDocument", "uri"]).and_then(Json::as_str).unwrap_or("").to_string();
+    let path = uri_to_path(&uri);
+
+    // the document at the position the request is about
+    let text = path.as_ref().and_then(|path| documents.read_to_string(path).ok()).unwrap_or_default();
+    let line = message.at(&["params", "position", "line"]).and_then(Json::as_usize).unwrap_or(0);
+    let character = message.at(&["params", "position", "character"]).and_then(Json::as_usize).unwrap_or(0);
+    let offset = lsp_offset(&text, line, character);
+    let analysis = analyses.get(&uri);
+    // \catcode in the document changes what the cursor is on
+    let cat = analysis.map_or(&options.catcodes, |analysis| &analysis.catcodes);
+
+    match method {
+        "initialize" => Some(
+            "{\"capabilities\":{\"textDocumentSync\":1,\"hoverProvider\":true,\"definitionProvider\":true,\
+             \"completionProvider\":{\"triggerCharacters\":[\"\\\\\"]}},\
+             \"serverInfo\":{\"name\":\"texproc\",\"version\":\"".to_string() + VERSION + "\"}}",
+        ),
+        "shutdown" => Some("null".to_string()),
+        "textDocument/didOpen" | "textDocument/didChange" => {
+            let changed = match method {
+                "textDocument/didOpen" => message.at(&["params", "textDocument", "text"]),
+                // full sync, the last change holds the whole text
+                _ => match message.at(&["params", "contentChanges"]) {
+                    Some(Json::Array(changes)) => changes.last().and_then(|change| change.get("text")),
+                    _ => None,
+                },
+            };
+
+            if let (Some(path), Some(changed)) = (&path, changed.and_then(Json::as_str)) {
+                documents.insert(&path.display().to_string(), changed);
+                let analysis = lsp_analyze(options, documents, path);
+                notifications.push(format!(
+                    "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{{\"uri\":{},\"diagnostics\":{}}}}}",
+                    json_string(&uri),
+                    lsp_diagnostics_json(&analysis)
+                ));
+                analyses.insert(uri, analysis);
+            }
+            None
+        }
+        "textDocument/didClose" => {
+            if let Some(path) = &path {
+                documents.remove(&path.display().to_string());
+            }
+            analyses.remove(&uri);
+            notifications.push(format!(
+                "{{\"jsonrpc\":\"2.0\",\"method\":\"textDocument/publishDiagnostics\",\"params\":{{\"uri\":{},\"diagnostics\":[]}}}}",
+                json_string(&uri)
+            ));
+            None
+        }
+        "textDocument/hover" => {
+            let node = match (lsp_target(&text, offset, cat), analysis) {
+                (Some(LspTarget::Command(name)), Some(analysis)) => find_macro(&analysis.macros, &name),
+                _ => None,
+            };
+            Some(match node {
+                Some(node) => format!(
+                    "{{\"contents\":{{\"kind\":\"markdown\",\"value\":{}}}}}",
+                    json_string(&format!("```\n{}\n```", describe_macro(node)))
+                ),
+                None => "null".to_string(),
+            })
+        }
+        "textDocument/definition" => {
+            let target = match lsp_target(&text, offset, cat) {
+                Some(LspTarget::Command(name)) => analysis
+                    .and_then(|analysis| find_macro(&analysis.macros, &name))
+                    .and_then(|node| node.location.as_ref())
+                    .map(|location| (PathBuf::from(&location.file), location.line, location.col)),
+                Some(LspTarget::Include(file)) => {
+                    // found the same way \include finds it, starting next to the document
+                    let mut ctx = new_context(options);
+                    ctx.resolver = Box::new(EditorResolver { documents: documents.clone() });
+                    let base = path.as_ref().and_then(|path| path.parent()).map(Path::to_path_buf);
+                    candidate_paths(&file, base.as_deref(), &ctx)
+                        .into_iter()
+                        .find(|candidate| ctx.resolver.is_file(candidate))
+                        .map(|found| (found, 1, 1))
+                }
+                None => None,
+            };
+            Some(match target {
+                Some((file, line, col)) => format!(
+                    "{{\"uri\":{},\"range\":{}}}",
+                    json_string(&path_to_uri(&file)),
+                    lsp_range(
+                        &EditorResolver { documents: documents.clone() }.read_to_string(&file).unwrap_or_default(),
+                        (line, col),
+                        None
+                    )
+                ),
+                None => "null".to_string(),
+            })
+        }
+        "textDocument/completion" => {
+            let mut items = Vec::new();
+            let mut current = analysis.map_or(&None, |analysis| &analysis.macros);
+            while let Some(ref node) = current {
+                items.push(format!(
+                    "{{\"label\":{},\"kind\":3,\"detail\":{},\"documentation\":{}}}",
+                    json_string(&node.name),
+                    json_string(&format!("\\{}{}", node.name, node.params.as_deref().unwrap_or("{#}"))),
+                    json_string(&node.value)
+                ));
+                current = &node.next;
+            }
+            Some(format!("[{}]", items.join(",")))
+        }
+        _ => None,
+    }
+}
 
-                content.extend(input[index_holder_if+1..].chars());
+// one message with its header from the editor, None at the end of input
+fn lsp_read(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
 
-                content = process(&content, head, initial_string);
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
 
+    let length = match length {
+        Some(length) => length,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length")),
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
 
-                // add the result to the output
-                output.push_str(&content);
+fn lsp_write(output: &mut impl Write, message: &str) -> io::Result<()> {
+    write!(output, "Content-Length: {}\r\n\r\n{}", message.len(), message)?;
+    output.flush()
+}
 
-                content.clear();
+// texproc lsp: a language server over stdin and stdout. documents are processed
+// as they change, with the documents the editor has open read from the editor
+fn lsp(options: &Options) -> io::Result<()> {
+    let mut input = stdin().lock();
+    let mut output = io::stdout().lock();
+    let mut documents = MemoryResolver::default();
+    let mut analyses = HashMap::new();
+    let mut shut_down = false;
+
+    while let Some(body) = lsp_read(&mut input)? {
+        let message = match parse_json(&body) {
+            Ok(message) => message,
+            Err(err) => {
+                let reply = format!("{{\"jsonrpc\":\"2.0\",\"id\":null,\"error\":{{\"code\":-32700,\"message\":{}}}}}", json_string(&err));
+                lsp_write(&mut output, &reply)?;
+                continue;
+            }
+        };
 
-                return output;
+        match message.get("method").and_then(Json::as_str) {
+            Some("exit") => std::process::exit(if shut_down { 0 } else { 1 }),
+            Some("shutdown") => shut_down = true,
+            _ => {}
+        }
 
-            }
+        let mut notifications = Vec::new();
+        let result = lsp_handle(&message, options, &mut documents, &mut analyses, &mut notifications);
 
+        // requests have an id and always get an answer, notifications don't
+        if let Some(id) = message.get("id") {
+            let reply = match result {
+                Some(result) => format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"result\":{}}}", id, result),
+                None => format!("{{\"jsonrpc\":\"2.0\",\"id\":{},\"error\":{{\"code\":-32601,\"message\":\"method not found\"}}}}", id),
+            };
+            lsp_write(&mut output, &reply)?;
+        }
+        for notification in &notifications {
+            lsp_write(&mut output, notification)?;
+        }
+    }
 
-            State::IfDef => {
+    Ok(())
+}
 
-                if c != '{' {
-                    die!("missing condition");
-                }
+fn run() -> io::Result<()> {
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-                stop = brace_balance_end(input, i);
+    // a bad command line is reported in the format it asks for too
+    let format = requested_error_format(&args);
 
-                arg1.clear();
-                arg1.extend(input[i+1..stop].chars());
+    if format == ErrorFormat::Text {
+        return execute(&parse_args(&args));
+    }
 
-                //SECOND ARG
+    // main only knows how to print text, anything else is reported from here
+    match panic::catch_unwind(panic::AssertUnwindSafe(|| execute(&parse_args(&args)))) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => {
+            eprintln!("{}", diagnostic_json(&Failure::new(ErrorKind::Io, err.to_string()).diagnostic));
+            std::process::exit(ErrorKind::Io.exit_code());
+        }
+        Err(payload) => std::process::exit(report_failure(&*payload, format)),
+    }
+}
 
-                //this should NOW be pointing to right after the first brace pair
-                i = stop + 1;
+// the --error-format among args, found before they are parsed. the last one counts
+fn requested_error_format(args: &[String]) -> ErrorFormat {
+    let mut format = ErrorFormat::Text;
 
-                if input.as_bytes().get(i) != Some(&b'{') {
-                    die!("missing then");
-                }
+    for (k, arg) in args.iter().enumerate() {
+        let value = match arg.strip_prefix("--error-format") {
+            Some("") => args.get(k + 1).map(String::as_str),
+            Some(tail) => tail.strip_prefix('='),
+            None => None,
+        };
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+        match value {
+            Some("json") => format = ErrorFormat::Json,
+            Some("text") => format = ErrorFormat::Text,
+            _ => {}
+        }
+    }
 
-                stop = brace_balance_end(input, i);
+    format
+}
 
-                arg2.clear();
-                arg2.extend(input[i+1..stop].chars());
+// does what the options ask for
+fn execute(options: &Options) -> io::Result<()> {
+    if options.lsp {
+        return lsp(options);
+    }
 
-                //now pointing to right after the second brace pair
-                i = stop + 1;
+    if options.repl {
+        return repl(options);
+    }
 
-                //THIRD ARG
+    if options.watch {
+        watch(options);
+    }
 
-                if input.as_bytes().get(i) != Some(&b'{') {
-                    die!("missing else");
-                }
+    let mut ctx = new_context(options);
+    process_inputs(options, &mut ctx)
+}
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+// exit status and message for a panic that ended processing
+fn failure_status(payload: &(dyn Any + Send)) -> (i32, String) {
+    if let Some(failure) = payload.downcast_ref::<Failure>() {
+        return (failure.kind.exit_code(), failure.diagnostic.message.clone());
+    }
 
-                stop = brace_balance_end(input, i);
+    // anything else is a bug, say what the panic said
+    let message = payload.downcast_ref::<&str>().map(|m| m.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default();
+    (EXIT_INTERNAL, format!("internal error {}", message))
+}
 
-                arg3.clear();
-                arg3.extend(input[i+1..stop].chars());
+// make treats spaces, # and $ in a rule specially
+fn make_escape(path: &str) -> String {
+    path.replace('$', "$$").replace('#', "\\#").replace(' ', "\\ ")
+}
 
+// "target: input include ..." for every file that was read, each one once
+fn dependency_rule(target: &str, ctx: &Context) -> String {
+    let mut rule = format!("{}:", make_escape(target));
+    let mut seen = HashSet::new();
 
-                let index_holder23 = stop; 
+    for file in &ctx.files {
+        if let (Some(path), Some(canonical)) = (&file.path, &file.canonical) {
+            if seen.insert(canonical) {
+                rule.push_str(" \\\n  ");
+                rule.push_str(&make_escape(&path.display().to_string()));
+            }
+        }
+    }
 
+    rule.push('\n');
+    rule
+}
 
-                // Determine the content based on arg1's condition (non-empty means true)
-                let condition_met = find_macro_value(head, &arg1).is_some();
+fn plural(count: usize, word: &str) -> String {
+    format!("{} {}{}", count, word, if count == 1 { "" } else { "s" })
+}
 
+// prints a panic that ended processing and gives the exit status for it
+fn report_failure(payload: &(dyn Any + Send), format: ErrorFormat) -> i32 {
+    let (status, message) = failure_status(payload);
+    let failure = payload.downcast_ref::<Failure>();
 
-                let mut content_ifdef = if condition_met {
-                    arg2.clone() // Clone to get a mutable copy
-                } else {
-                    arg3.clone() // Clone to get a mutable copy
-                };
+    if failure.is_some_and(|failure| failure.reported) {
+        return status;
+    }
 
-                content_ifdef.extend(input[index_holder23+1..].chars());
+    match (format, failure) {
+        // a failure that knows where it happened shows it like the collected diagnostics
+        (ErrorFormat::Text, Some(failure)) if failure.diagnostic.location.is_some() => {
+            eprintln!("proj3: {}", failure.diagnostic.render(color_stderr()))
+        }
+        (ErrorFormat::Text, _) => eprintln!("proj3: {}", message),
+        (ErrorFormat::Json, Some(failure)) => eprintln!("{}", diagnostic_json(&failure.diagnostic)),
+        (ErrorFormat::Json, None) => eprintln!(
+            "{{\"severity\": \"error\", \"code\": \"internal\", \"message\": {}, \"file\": null, \"span\": null, \"notes\": [], \"help\": null}}",
+            json_string(&message)
+        ),
+    }
+    status
+}
 
-                content_ifdef = process(&content_ifdef, head, initial_string);
+fn print_diagnostics(diagnostics: &[Diagnostic], format: ErrorFormat) {
+    let color = color_stderr();
+    for diagnostic in diagnostics {
+        match format {
+            ErrorFormat::Text => eprintln!("proj3: {}", diagnostic.render(color)),
+            ErrorFormat::Json => eprintln!("{}", diagnostic_json(diagnostic)),
+        }
+    }
+}
 
-                // add the result to the output
-                output.push_str(&content_ifdef);
+// prints what was collected along the way and a count. any error fails the run
+// with the status of the first one
+fn report_diagnostics(diagnostics: &[Diagnostic], format: ErrorFormat) {
+    if diagnostics.is_empty() {
+        return;
+    }
 
-                content_ifdef.clear();
+    print_diagnostics(diagnostics, format);
+
+    let mut errors = diagnostics.iter().filter_map(|d| match d.severity {
+        Severity::Error(kind) => Some(kind),
+        Severity::Warning => None,
+    });
+    let first_error = errors.next();
+    let error_count = first_error.map_or(0, |_| 1 + errors.count());
+    let summary = format!("{}, {}", plural(error_count, "error"), plural(diagnostics.len() - error_count, "warning"));
+
+    // json has nothing but the diagnostics
+    match (first_error, format) {
+        (Some(kind), ErrorFormat::Text) => panic::panic_any(Failure::new(kind, summary)),
+        (Some(kind), ErrorFormat::Json) => panic::panic_any(Failure { reported: true, ..Failure::new(kind, summary) }),
+        (None, ErrorFormat::Text) => eprintln!("proj3: {}", summary),
+        (None, ErrorFormat::Json) => {}
+    }
+}
 
-                return output;
 
-            }
+fn main() {
 
-            State::ExpandAfter => {
+    panic::set_hook(Box::new(|_| {}));
 
-                if c != '{' {
-                    die!("missing before");
-                }
+    // every expansion is a nested call to process, so the work happens on a thread with room for that
+    let result = match std::thread::Builder::new().stack_size(ENGINE_STACK_SIZE).spawn(run) {
+        Ok(engine) => engine.join(),
+        Err(_) => Ok(run()),
+    };
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => {
+            eprintln!("proj3: {}", err);
+            std::process::exit(ErrorKind::Io.exit_code());
+        }
+        Err(payload) => std::process::exit(report_failure(&*payload, ErrorFormat::Text)),
+    }
+}
 
-                stop = brace_balance_end(input, i);
 
-                arg1.clear();
-                arg1.extend(input[i+1..stop].chars());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                //SECOND ARG
+    // a context that reads its files from memory instead of the disk
+    fn memory_context(files: &[(&str, &str)]) -> Context {
+        let mut resolver = MemoryResolver::default();
+        for (path, contents) in files {
+            resolver.insert(path, contents);
+        }
 
-                //this should NOW be pointing to right after the first brace pair
-                i = stop + 1;
+        Context {
+            resolver: Box::new(resolver),
+            ..Context::default()
+        }
+    }
 
-                if input.as_bytes().get(i) != Some(&b'{') {
-                    die!("missing then");
-                }
+    // expands the file main the way process_inputs does
+    fn expand(ctx: &mut Context, main: &str) -> String {
+        expand_with(ctx, &mut None, main)
+    }
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+    // the same, starting from the macros in head and leaving them there
+    fn expand_with(ctx: &mut Context, head: &mut Option<Box<MacroNode>>, main: &str) -> String {
+        let id = initial_file_input(main, None, None, ctx).unwrap_or_else(|failure| failure.raise());
+        let text = ctx.files[id].text.clone();
+        ctx.layout = Layout::file(id, text.len());
+        ctx.started = Some(Instant::now());
 
-                stop = brace_balance_end(input, i);
+        let processed = process(&text, head, ctx);
+        process_backslashes(&processed, head, &ctx.catcodes).0
+    }
 
-                arg2.clear();
-                arg2.extend(input[i+1..stop].chars());
+    // somewhere to write a trace that can be read back afterwards
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
 
-                //now pointing to right after the second brace pair
-                i = stop + 1;
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
 
-                let hold_here = i;
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
-                // process the second arg
-                let result_after = process(&arg2, head, initial_string);
+    #[test]
+    fn tracing_logs_the_steps_between_tracingon_and_tracingoff() {
+        let mut ctx = memory_context(&[(
+            "main.tex",
+            "\\def{w}{<#>}\\w{a}\n\\tracingon \\w{\\w{b}}\\if{}{x}{y}\\tracingoff \\w{c}",
+        )]);
+        let trace = SharedBuffer::default();
+        ctx.trace_file = Some(Box::new(trace.clone()));
+
+        assert_eq!(expand(&mut ctx, "main.tex"), "<a>\n <<b>>y <c>");
+        assert_eq!(String::from_utf8(trace.0.take()).unwrap(), concat!(
+            "main.tex:2:12: [0] \\w{\\w{b}} -> <\\w{b}>\n",
+            "main.tex:2:12: [1] \\w{b} -> <b>\n",
+            "main.tex:2:21: [0] \\if{}{x}{y} -> y\n",
+        ));
+    }
 
-                // add the result to the arg1
-                arg1.push_str(&result_after);
+    #[test]
+    fn delimited_parameters_read_up_to_their_delimiters() {
+        let mut ctx = memory_context(&[(
+            "main.tex",
+            "\\def{pair}(#1,#2){<#2|#1>}\\pair(a,b) \\pair({x,y},z) \\def{two}#1#2{(#1)(#2)}\\two{xy}{z}",
+        )]);
 
-                arg1.extend(input[hold_here..].chars());
+        assert_eq!(expand(&mut ctx, "main.tex"), "<b|a> <z|x,y> (xy)(z)");
+    }
 
-                // process the second arg and rest of string
-                let result_before = process(&arg1, head, initial_string);
+    #[test]
+    fn delimited_parameters_that_dont_match_are_errors() {
+        let mut ctx = memory_context(&[("main.tex", "\\def{bad}(#1,#3){}\n\\def{pair}(#1,#2){#1}\\pair(a b")]);
+        ctx.recover = true;
+        expand(&mut ctx, "main.tex");
+
+        let messages: Vec<&str> = ctx.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, [
+            "parameters must be numbered #1 to #9 in order",
+            "use of \\pair doesn't match its definition: no ',' found to end argument 1",
+        ]);
+    }
 
-                // add the result to the output
-                output.push_str(&result_before);
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
 
-                //OUTPUT
+    #[test]
+    fn search_paths_are_tried_in_order() {
+        std::env::set_var("TEXPROC_PATH", "third");
+        let mut ctx = new_context(&parse_args(&args(&["-I", "first", "-I", "second", "main.tex"])));
+        std::env::remove_var("TEXPROC_PATH");
+        ctx.resolver = memory_context(&[
+            ("main.tex", "\\include{a.tex} \\include{b.tex} \\include{c.tex} \\include{d.tex}"),
+            ("first/a.tex", "first"),
+            ("second/a.tex", "second"),
+            ("third/a.tex", "third"),
+            ("second/b.tex", "second"),
+            ("third/b.tex", "third"),
+            ("third/c.tex", "third"),
+        ]).resolver;
+        ctx.recover = true;
+
+        assert_eq!(expand(&mut ctx, "main.tex"), "first second third ");
+
+        // diagnostics name the file that was actually read
+        let names: Vec<&str> = ctx.files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(names, ["main.tex", "first/a.tex", "second/b.tex", "third/c.tex"]);
+        let messages: Vec<&str> = ctx.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(messages, ["file dne: d.tex (looked for d.tex, first/d.tex, second/d.tex, third/d.tex)"]);
+    }
 
-                return output;
+    #[test]
+    fn includes_are_found_next_to_the_file_including_them() {
+        let mut ctx = memory_context(&[
+            ("book/main.tex", "[\\include{chapters/intro.tex}]"),
+            ("book/chapters/intro.tex", "\\include{note.tex} \\include{../title.tex} \\include{lib.tex} \\include{top.tex}"),
+            ("book/chapters/note.tex", "next to intro"),
+            ("note.tex", "in the working directory"),
+            ("book/title.tex", "title"),
+            ("lib/lib.tex", "from -I"),
+            ("top.tex", "from the working directory"),
+        ]);
+        ctx.search_paths = vec![PathBuf::from("lib")];
+
+        assert_eq!(
+            expand(&mut ctx, "book/main.tex"),
+            "[next to intro title from -I from the working directory]"
+        );
+    }
 
-            }
+    #[test]
+    fn include_cycles_are_reported_with_the_chain() {
+        let mut ctx = memory_context(&[
+            ("a.tex", "A[\\include{b.tex}]"),
+            ("b.tex", "B\\include{c.tex}"),
+            ("c.tex", "C\\include{a.tex}"),
+        ]);
+        ctx.recover = true;
+
+        assert_eq!(expand(&mut ctx, "a.tex"), "A[BC]");
+        assert_eq!(ctx.diagnostics.len(), 1);
+        let cycle = &ctx.diagnostics[0];
+        assert_eq!(cycle.message, "include cycle: a.tex is already being included");
+        assert_eq!(cycle.location.as_ref().map(|location| (location.file.as_str(), location.col)), Some(("c.tex", 2)));
+        assert_eq!(cycle.notes, ["c.tex was included from b.tex", "b.tex was included from a.tex"]);
+    }
 
-            State::Include => {
+    #[test]
+    fn includeonce_reads_a_file_once_however_it_is_named() {
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\includeonce{h.tex}\\includeonce{h.tex}\\includeonce{./h.tex}\\h{}"),
+            ("h.tex", "\\def{h}{H}h"),
+        ]);
 
-                if c != '{' {
-                    die!("missing before");
-                }
+        assert_eq!(expand(&mut ctx, "main.tex"), "hH");
+        assert!(ctx.diagnostics.is_empty());
+    }
 
-                if !is_brace_balanced(input, i) {
-                    die!("Not brace balanced");
-                }
+    #[test]
+    fn command_line_options_take_their_values_either_way() {
+        let options = parse_args(&args(&[
+            "-o", "out.txt", "-Dgreet=hi #", "-D", "flag", "-Dgone=1", "-U", "gone", "--max-steps", "5",
+            "--max-output=9", "a.tex", "--",
+        ]));
+
+        assert_eq!(options.output.as_deref(), Some("out.txt"));
+        assert!(matches!(options.inputs.as_slice(), [Input::File(file), Input::Stdin] if file == "a.tex"));
+        assert_eq!((options.limits.max_steps, options.limits.max_output), (Some(5), Some(9)));
+
+        let head = initial_macros(&options);
+        let defined: Vec<String> = std::iter::successors(head.as_deref(), |node| node.next.as_deref())
+            .map(|node| format!("{}={}", node.name, node.value))
+            .collect();
+        assert_eq!(defined, ["greet=hi #", "flag=1"]);
+    }
 
-                stop = brace_balance_end(input, i);
+    #[test]
+    fn bad_command_lines_are_usage_errors_before_anything_is_read() {
+        for bad in [
+            &["--bogus", "a.tex"][..],
+            &["-D", "no-dash=1"],
+            &["--error-format=xml"],
+            &["--max-depth", "lots"],
+            &["-o"],
+            // the state file isn't looked at until the rest is known to be good
+            &["--load-state", "does-not-exist.state", "--bogus"],
+        ] {
+            let payload = panic::catch_unwind(|| parse_args(&args(bad))).err().unwrap();
+            assert_eq!(failure_status(&*payload).0, 2, "{:?}", bad);
+        }
+    }
 
-                arg1.clear();
+    #[test]
+    fn lenient_mode_passes_unknown_commands_through_verbatim() {
+        let mut ctx = new_context(&parse_args(&args(&["--passthrough=textbf", "main.tex"])));
+        ctx.resolver = memory_context(&[(
+            "main.tex",
+            "\\def{x}{X}\\section{Intro \\x{}}{two} \\section*{b} \\textbf{50\\% off \\{x\\}} \\x{}",
+        )]).resolver;
+
+        assert_eq!(
+            expand(&mut ctx, "main.tex"),
+            "\\section{Intro \\x{}}{two} \\section*{b} \\textbf{50\\% off \\{x\\}} X"
+        );
+
+        // a warning the first time for commands not on the list
+        let warnings: Vec<&str> = ctx.diagnostics.iter().map(|diagnostic| diagnostic.message.as_str()).collect();
+        assert_eq!(warnings, ["unknown command \\section passed through"]);
+    }
 
-                for k in (i + 1)..stop {
-                    let c = input.as_bytes()[k] as char; // Convert byte to char for comparison
-                
-                    // Check if the character is not allowed
-                    if !(c.is_alphanumeric() || c == '_' || c == '/' || c == '-' || c == '.') {
-                        die!("File name contains invalid characters.");
-                    }
-                
-                    // add the character to arg1
-                    arg1.push(c);
-                }
+    #[test]
+    fn make_rules_list_every_file_read_once() {
+        let options = parse_args(&args(&["-M", "my book/$main#.tex"]));
+        assert_eq!(options.deps_target.as_deref(), Some("my book/$main#.out"));
+        assert_eq!(parse_args(&args(&["-M", "-o", "out.txt", "a.tex"])).deps_target, None);
+
+        let mut ctx = new_context(&options);
+        ctx.resolver = memory_context(&[
+            ("my book/$main#.tex", "\\include{chapters/intro.tex}\\include{./chapters/intro.tex}"),
+            ("my book/chapters/intro.tex", "\\include{note.tex}"),
+            ("my book/chapters/note.tex", "note "),
+        ]).resolver;
+        expand(&mut ctx, "my book/$main#.tex");
+
+        assert_eq!(dependency_rule("my book/$main#.out", &ctx), concat!(
+            "my\\ book/$$main\\#.out: \\\n",
+            "  my\\ book/$$main\\#.tex \\\n",
+            "  my\\ book/chapters/intro.tex \\\n",
+            "  my\\ book/chapters/note.tex\n",
+        ));
+    }
 
-                let path = arg1.trim(); // trim any  whitespace
-                //RUST FUNCTION
+    #[test]
+    fn repl_entries_continue_until_their_groups_close() {
+        let cat = Catcodes::default();
 
-                arg2 = initial_file_input(&path).expect("REASON");
+        assert!(repl_entry_complete("\\def{a}{x}\n", &cat));
+        assert!(!repl_entry_complete("\\def{a}{x\n", &cat));
+        assert!(!repl_entry_complete("\\def{a}{\n  x {y}\n", &cat));
+        assert!(repl_entry_complete("an escaped \\{ opens nothing\n", &cat));
+        assert!(repl_entry_complete("\\def{a}{\n  x {y}\n}\n", &cat));
+    }
 
-                stop = brace_balance_end(input, i);
+    #[test]
+    fn repl_keeps_macros_across_entries_and_errors() {
+        let mut ctx = Context::default();
+        let mut head = None;
 
-                //now pointing to right after the first brace pair
-                i = stop + 1;
+        for entry in ["\\def{a}{A}\n", "\\def{b}{B}\\undefined{}\n", "\\a{}\\b{}\n"] {
+            let id = ctx.add_source("<repl>", None, entry.to_string());
+            repl_expand(id, &mut head, &mut ctx);
+        }
 
-                let include_hold_here = i;
+        assert!(find_macro(&head, "a").is_some_and(|node| node.uses == 1));
+        assert!(find_macro(&head, "b").is_some_and(|node| node.uses == 1));
+        // each entry stands on its own, nothing is left over for the next
+        assert!(ctx.diagnostics.is_empty());
+        assert_eq!(ctx.open_groups, 0);
+    }
 
+    #[test]
+    fn macros_json_format() {
+        let options = parse_args(&args(&["-Dversion=1.0", "main.tex"]));
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\def{greet}{\"hi\" #}\n  \\def{pair}(#1,#2){#2\\\\#1}"),
+        ]);
+        let mut head = initial_macros(&options);
+        expand_with(&mut ctx, &mut head, "main.tex");
+
+        assert_eq!(macros_json(&head, &ctx.catcodes), concat!(
+            "{\"macros\": [\n",
+            "  {\"name\": \"version\", \"params\": null, \"body\": \"1.0\", \"arity\": 1, \"location\": null},\n",
+            "  {\"name\": \"greet\", \"params\": null, \"body\": \"\\\"hi\\\" #\", \"arity\": 1, ",
+            "\"location\": {\"file\": \"main.tex\", \"line\": 1, \"col\": 1}},\n",
+            "  {\"name\": \"pair\", \"params\": \"(#1,#2)\", \"body\": \"#2\\\\\\\\#1\", \"arity\": 2, ",
+            "\"location\": {\"file\": \"main.tex\", \"line\": 2, \"col\": 3}}\n",
+            "]}\n",
+        ));
+        assert_eq!(macros_json(&None, &ctx.catcodes), "{\"macros\": []}\n");
+    }
 
-                // process the include
-                //let result_after = process(&arg2, head);
+    #[test]
+    fn includes_resolve_through_memory() {
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\def{greet}{hello #}\\greet{\\include{parts/name.tex}}!"),
+            ("parts/name.tex", "\\include{nested.tex}"),
+            ("parts/nested.tex", "world"),
+        ]);
 
-                arg2.extend(input[include_hold_here..].chars());
+        assert_eq!(expand(&mut ctx, "main.tex"), "hello world!");
+        assert!(ctx.files.iter().all(|file| file.path.is_some()));
+    }
 
-                // Process the second arg and rest of string
-                let result_winclude = process(&arg2, head, initial_string);
+    // a context confined to the directory root that keeps going after errors
+    fn sandboxed_context(files: &[(&str, &str)]) -> Context {
+        let mut ctx = memory_context(files);
+        ctx.sandbox = Some(PathBuf::from("root"));
+        ctx.recover = true;
+        ctx
+    }
 
-                // add the result to the output
-                output.push_str(&result_winclude);
+    fn codes(ctx: &Context) -> Vec<&'static str> {
+        ctx.diagnostics.iter().map(|diagnostic| diagnostic.code).collect()
+    }
 
-                //OUTPUT
+    #[test]
+    fn sandbox_keeps_includes_from_climbing_out() {
+        let mut ctx = sandboxed_context(&[
+            ("root/main.tex", "[\\include{sub/ok.tex}][\\include{../secret.tex}][\\include{sub/../../secret.tex}][\\include{/secret.tex}]"),
+            ("root/sub/ok.tex", "ok"),
+            ("secret.tex", "secret"),
+        ]);
 
-                return output;
+        assert_eq!(expand(&mut ctx, "root/main.tex"), "[ok][][][]");
+        assert_eq!(codes(&ctx), ["sandbox", "sandbox", "sandbox"]);
+    }
 
-            }
+    #[test]
+    fn sandbox_applies_to_files_found_through_search_paths() {
+        let mut ctx = sandboxed_context(&[
+            ("root/main.tex", "[\\include{inside.tex}][\\include{outside.tex}]"),
+            ("root/lib/inside.tex", "in"),
+            ("lib/outside.tex", "out"),
+        ]);
+        ctx.search_paths = vec![PathBuf::from("root/lib"), PathBuf::from("lib")];
+
+        assert_eq!(expand(&mut ctx, "root/main.tex"), "[in][]");
+        assert_eq!(codes(&ctx), ["sandbox"]);
+    }
 
-        }
-        //INCREMENT!
-        i += 1;
+    #[cfg(unix)]
+    #[test]
+    fn sandbox_follows_links_out_of_the_root() {
+        let dir = std::env::temp_dir().join(format!("texproc-sandbox-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(dir.join("secret.tex"), "secret").unwrap();
+        std::fs::write(root.join("main.tex"), "[\\include{link.tex}]").unwrap();
+        let _ = std::fs::remove_file(root.join("link.tex"));
+        std::os::unix::fs::symlink(dir.join("secret.tex"), root.join("link.tex")).unwrap();
+
+        let mut ctx = Context {
+            sandbox: Some(root.canonicalize().unwrap()),
+            recover: true,
+            ..Context::default()
+        };
+        let output = expand(&mut ctx, &root.join("main.tex").display().to_string());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(output, "[]");
+        assert_eq!(codes(&ctx), ["sandbox"]);
+    }
 
+    // the exit status and message of a run that stops on a failure
+    fn failed_status(ctx: &mut Context, main: &str) -> (i32, String) {
+        let payload = panic::catch_unwind(panic::AssertUnwindSafe(|| expand(ctx, main))).unwrap_err();
+        failure_status(&*payload)
     }
 
-    return output;
+    #[test]
+    fn each_kind_of_failure_has_its_own_status() {
+        let usage = panic::catch_unwind(|| parse_args(&args(&["--bogus"]))).err().unwrap();
+        assert_eq!(failure_status(&*usage).0, 2);
+
+        for (main, status) in [
+            ("\\include{missing.tex}", 3),
+            ("\\def{a}{b", 4),
+            ("\\if{a}", 4),
+            ("\\nosuchmacro{}", 5),
+            ("\\def{a}{1}\\def{a}{2}", 5),
+            ("\\include{/etc/passwd}", 6),
+        ] {
+            let mut ctx = memory_context(&[("root/main.tex", main)]);
+            ctx.sandbox = Some(PathBuf::from("root"));
+            assert_eq!(failed_status(&mut ctx, "root/main.tex").0, status, "{}", main);
+        }
 
-}
+        // anything that isn't one of ours is a bug
+        let bug = panic::catch_unwind(|| panic!("oops")).err().unwrap();
+        assert_eq!(failure_status(&*bug), (101, "internal error oops".to_string()));
+    }
 
+    #[test]
+    fn limits_stop_processing_with_status_7() {
+        let mut ctx = memory_context(&[("main.tex", "\\def{loop}{\\loop{}}\\loop{}")]);
+        ctx.limits.max_depth = Some(5);
+        assert_eq!(failed_status(&mut ctx, "main.tex"), (7, "limit exceeded: expansions nested more than 5 deep".to_string()));
+
+        let mut ctx = memory_context(&[("main.tex", "\\def{a}{x}\\a{}\\a{}\\a{}")]);
+        ctx.limits.max_steps = Some(3);
+        assert_eq!(failed_status(&mut ctx, "main.tex"), (7, "limit exceeded: more than 3 expansion steps".to_string()));
+
+        let mut ctx = memory_context(&[("main.tex", "0123456789")]);
+        ctx.limits.max_output = Some(4);
+        assert_eq!(failed_status(&mut ctx, "main.tex"), (7, "limit exceeded: output is over 4 bytes".to_string()));
+
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\include{a.tex}"),
+            ("a.tex", "\\include{b.tex}"),
+            ("b.tex", "\\include{c.tex}"),
+            ("c.tex", "deep"),
+        ]);
+        ctx.limits.max_include_depth = Some(2);
+        assert_eq!(failed_status(&mut ctx, "main.tex"), (7, "limit exceeded: includes nested more than 2 deep".to_string()));
+
+        let mut ctx = memory_context(&[("main.tex", "\\def{a}{x}\\a{}")]);
+        ctx.limits.timeout = Some(Duration::ZERO);
+        assert_eq!(failed_status(&mut ctx, "main.tex"), (7, "limit exceeded: processing took longer than 0.000s".to_string()));
+    }
 
+    #[test]
+    fn limits_leave_text_within_them_alone() {
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\def{a}{x}\\a{}\\include{a.tex}"),
+            ("a.tex", "\\include{b.tex}"),
+            ("b.tex", "yz"),
+        ]);
+        ctx.limits = Limits {
+            max_depth: Some(2),
+            max_steps: Some(4),
+            max_output: Some(3),
+            max_include_depth: Some(2),
+            timeout: Some(Duration::from_secs(60)),
+        };
+
+        assert_eq!(expand(&mut ctx, "main.tex"), "xyz");
+    }
 
-//read in file input into a string and remove comments thru machine
-fn initial_file_input(file_path: &str) -> io::Result<String> {
+    #[test]
+    fn check_reports_every_problem_around_a_bad_include() {
+        let mut ctx = new_context(&parse_args(&args(&["--check", "main.tex"])));
+        ctx.resolver = memory_context(&[
+            ("main.tex", "\\undefined{x}\n\\include{missing.tex}\n\\def{a}{open\n\\include{main.tex}\n"),
+        ]).resolver;
+
+        expand(&mut ctx, "main.tex");
+
+        let found: Vec<(&str, usize)> = ctx
+            .diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.code, diagnostic.location.as_ref().unwrap().line))
+            .collect();
+        assert_eq!(found, [("semantic", 1), ("io", 2), ("syntax", 3), ("semantic", 4)]);
+    }
 
-    match File::open(file_path) {
-        Ok(mut file) => {
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            let processed_contents = process_comments(&contents);
-            Ok(processed_contents)
-        },
-        Err(_) => {
-            die!("file dne on cml");
-        }
+    #[test]
+    fn escapes_decide_which_braces_count() {
+        let cat = Catcodes::default();
+
+        assert_eq!(match_group("{a\\{b}", 0, &cat), Ok(5));
+        assert_eq!(match_group("{a\\\\{b}}", 0, &cat), Ok(7));
+        assert_eq!(match_group("{a\\\\\\}b}", 0, &cat), Ok(7));
+        assert_eq!(match_group("{a\\\\}b}", 0, &cat), Ok(4));
+        assert_eq!(match_group("{a{b", 0, &cat), Err(Unbalanced::Unclosed(2)));
+        assert_eq!(match_group("}{a}", 0, &cat), Err(Unbalanced::Extra(0)));
+        assert_eq!(match_group("ab", 0, &cat), Err(Unbalanced::NoGroup));
     }
-}
 
+    #[test]
+    fn stray_closing_braces_are_reported_where_they_are() {
+        let mut ctx = memory_context(&[
+            ("main.tex", "a}b \\def{x}{y}}\n\\if{a}}{b}{c}\n{\\x{}} \\{ \\\\{z} \\}\n"),
+        ]);
+        ctx.recover = true;
+
+        expand(&mut ctx, "main.tex");
+
+        let found: Vec<(&str, usize, usize)> = ctx
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                let location = diagnostic.location.as_ref().unwrap();
+                (diagnostic.message.as_str(), location.line, location.col)
+            })
+            .collect();
+        assert_eq!(found, [
+            ("closing brace without a group to close passed through", 1, 2),
+            ("closing brace without a group to close passed through", 1, 15),
+            ("missing then", 2, 7),
+        ]);
+    }
 
-fn main() -> io::Result<()> {
+    #[test]
+    fn stray_closing_braces_are_copied_through() {
+        let mut ctx = memory_context(&[("main.tex", "a } b")]);
 
-    panic::set_hook(Box::new(|_| {}));
+        assert_eq!(expand(&mut ctx, "main.tex"), "a } b");
+        assert_eq!(ctx.diagnostics.len(), 1);
+        assert_eq!(ctx.diagnostics[0].code, "unmatched-brace");
+        assert!(matches!(ctx.diagnostics[0].severity, Severity::Warning));
+    }
 
+    #[test]
+    fn lsp_ranges_count_utf16_units_up_to_the_end() {
+        let text = "caf\u{e9} \u{1F600} \\x{}\n";
+
+        assert_eq!(
+            lsp_range(text, (1, 8), Some((1, 12))),
+            "{\"start\":{\"line\":0,\"character\":8},\"end\":{\"line\":0,\"character\":12}}"
+        );
+        assert_eq!(
+            lsp_range(text, (1, 8), None),
+            "{\"start\":{\"line\":0,\"character\":8},\"end\":{\"line\":0,\"character\":9}}"
+        );
+    }
 
-    let mut combined_contents = String::new();
-    let args: Vec<String> = std::env::args().collect();
+    #[test]
+    fn saved_state_loads_back_the_same() {
+        let path = std::env::temp_dir().join(format!("texproc-state-{}", std::process::id()));
+        let path = path.display().to_string();
+
+        let mut ctx = memory_context(&[
+            ("preamble.tex", "\\def{greet}{hello #}\\def{pair}#1,#2.{<#2|#1>}\\catcode{!}{0}!def{bang}{x\ny}"),
+            ("main.tex", "!greet{you} \\pair a,b. \\bang{}"),
+        ]);
+        let mut head = None;
+        expand_with(&mut ctx, &mut head, "preamble.tex");
+        save_state(&path, &head, &ctx.catcodes);
+
+        let saved = load_state(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut loaded = memory_context(&[("main.tex", "!greet{you} \\pair a,b. \\bang{}")]);
+        loaded.catcodes = saved.catcodes;
+        let mut loaded_head = saved.macros;
+
+        let expected = expand_with(&mut ctx, &mut head, "main.tex");
+        assert_eq!(expected, "hello you <b| a> x\ny");
+        assert_eq!(expand_with(&mut loaded, &mut loaded_head, "main.tex"), expected);
+        assert_eq!(
+            describe_macro(find_macro(&loaded_head, "bang").unwrap()),
+            describe_macro(find_macro(&head, "bang").unwrap())
+        );
+    }
 
-    if args.len() > 1 {
-        // read from the files listed in the cml args
-        for file_path in &args[1..] {
-            let processed_contents = initial_file_input(file_path)?;
-            combined_contents.push_str(&processed_contents);
+    #[test]
+    fn corrupt_state_files_are_rejected() {
+        let header = format!("{}\n", STATE_HEADER);
+
+        for corrupt in [
+            "not a state file\n".to_string(),
+            format!("{}macro {} - 1 - 0 0\nab\n", header, usize::MAX),
+            format!("{}macro 5 - 1 - 0 0\nab\n", header),
+            format!("{}macro 1 - 1 - 0 0\nabc\n", header),
+            format!("{}catcode 65 0\n", header),
+            format!("{}catcode 33 0", header),
+        ] {
+            assert!(parse_state(&corrupt).is_err(), "{:?}", corrupt);
         }
-    } else {
-        // no files specified; read from stdin
-        let mut contents = String::new();
-        stdin().read_to_string(&mut contents)?;
-        // process the stdin contents and  remove comments
-        //not using initial FILE input bc no file
-        combined_contents = process_comments(&contents);
-    }
 
-    let mut my_head: Option<Box<MacroNode>> = None;
+        let path = std::env::temp_dir().join(format!("texproc-corrupt-{}", std::process::id()));
+        std::fs::write(&path, format!("{}macro {} - 1 - 0 0\nab\n", header, usize::MAX)).unwrap();
+        let payload = panic::catch_unwind(|| load_state(&path.display().to_string())).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
 
-    let processed = process(&combined_contents, &mut my_head, &combined_contents);
+        let (status, message) = failure_status(&*payload);
+        assert_eq!(status, 3);
+        assert!(message.starts_with("bad state file"), "{}", message);
+    }
 
-    let processed_final = process_backslashes(&processed, &mut my_head);
+    #[test]
+    fn source_map_json_format() {
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\def{hi}{Hi #!}\n\\hi{there} x\n\\include{ch.tex}"),
+            ("ch.tex", "caf\u{e9}\n"),
+        ]);
+        ctx.mappings = Some(Vec::new());
+
+        let id = initial_file_input("main.tex", None, None, &mut ctx).unwrap_or_else(|failure| failure.raise());
+        let text = ctx.files[id].text.clone();
+        ctx.layout = Layout::file(id, text.len());
+        let mut head = None;
+        let processed = process(&text, &mut head, &mut ctx);
+        let (output, offsets) = process_backslashes(&processed, &mut head, &ctx.catcodes);
+
+        assert_eq!(output, "\nHi there! x\ncaf\u{e9}\n");
+        assert_eq!(
+            source_map_json(&output, &offsets, &ctx),
+            concat!(
+                "{\"version\": 1,\n",
+                "  \"sources\": [\"main.tex\", \"ch.tex\"],\n",
+                "  \"mappings\": [\n",
+                "    {\"output\": {\"offset\": 0, \"line\": 1, \"col\": 1}, \"length\": 1, \"source\": 0, \"line\": 1, \"col\": 16, \"expansion\": null},\n",
+                "    {\"output\": {\"offset\": 1, \"line\": 2, \"col\": 1}, \"length\": 9, \"source\": 0, \"line\": 2, \"col\": 1, \"expansion\": \"hi\"},\n",
+                "    {\"output\": {\"offset\": 10, \"line\": 2, \"col\": 10}, \"length\": 3, \"source\": 0, \"line\": 2, \"col\": 11, \"expansion\": null},\n",
+                "    {\"output\": {\"offset\": 13, \"line\": 3, \"col\": 1}, \"length\": 6, \"source\": 1, \"line\": 1, \"col\": 1, \"expansion\": null}\n",
+                "  ]}\n",
+            )
+        );
+    }
 
-    // process the concatenated contents
-    print!("{}", processed_final);
+    #[test]
+    fn diagnostic_json_format() {
+        let mut ctx = memory_context(&[
+            ("main.tex", "\\def{hello}{hi}}\n\\include{ch.tex}"),
+            ("ch.tex", "x \\helo{}\n\"quoted\" \\if{a}"),
+        ]);
+        ctx.recover = true;
+        expand(&mut ctx, "main.tex");
+        ctx.diagnostics.push(Failure::new(ErrorKind::Io, "can't read x".to_string()).diagnostic);
+
+        let json: Vec<String> = ctx.diagnostics.iter().map(diagnostic_json).collect();
+        assert_eq!(json, [
+            concat!(
+                "{\"severity\": \"warning\", \"code\": \"unmatched-brace\", \"message\": \"closing brace without a group to close passed through\", ",
+                "\"file\": \"main.tex\", \"span\": {\"start\": {\"line\": 1, \"col\": 16}, \"end\": {\"line\": 1, \"col\": 16}}, \"notes\": [], \"help\": null}",
+            ),
+            concat!(
+                "{\"severity\": \"error\", \"code\": \"semantic\", \"message\": \"user-def arg not defined: helo\", ",
+                "\"file\": \"ch.tex\", \"span\": {\"start\": {\"line\": 1, \"col\": 3}, \"end\": {\"line\": 1, \"col\": 8}}, ",
+                "\"notes\": [\"ch.tex was included from main.tex\"], \"help\": \"did you mean \\\\hello?\"}",
+            ),
+            concat!(
+                "{\"severity\": \"error\", \"code\": \"syntax\", \"message\": \"missing then\", ",
+                "\"file\": \"ch.tex\", \"span\": {\"start\": {\"line\": 2, \"col\": 10}, \"end\": {\"line\": 2, \"col\": 10}}, ",
+                "\"notes\": [\"ch.tex was included from main.tex\"], \"help\": null}",
+            ),
+            "{\"severity\": \"error\", \"code\": \"io\", \"message\": \"can't read x\", \"file\": null, \"span\": null, \"notes\": [], \"help\": null}",
+        ]);
+    }
 
-    Ok(())
+    #[test]
+    fn unused_macros_go_by_file_not_by_name() {
+        let mut ctx = Context::default();
+        let mut head = None;
+        for entry in ["\\def{a}{1}", "\n\\def{b}{2}"] {
+            let id = ctx.add_source("<repl>", None, entry.to_string());
+            let text = ctx.files[id].text.clone();
+            ctx.layout = Layout::file(id, text.len());
+            process(&text, &mut head, &mut ctx);
+        }
+        // from a saved state, with the name of a file that was read
+        let saved = Location { file: "<repl>".to_string(), line: 1, col: 1, source: None };
+        add_or_update_macro(&mut head, "saved", "3", None, Some(saved));
+
+        let unused: Vec<(String, Vec<(usize, String)>)> = unused_macros(&head, &ctx)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.message, diagnostic.source))
+            .collect();
+        assert_eq!(unused, [
+            ("\\a is defined but never used".to_string(), vec![(1, "\\def{a}{1}".to_string())]),
+            ("\\b is defined but never used".to_string(), vec![(2, "\\def{b}{2}".to_string())]),
+        ]);
+    }
 }